@@ -0,0 +1,906 @@
+//! Bale server for tests, speaking gRPC-Web on localhost.
+//!
+//! Implements just enough of the `Auth`, `Configs`, `Messaging`, `MavizStream`
+//! and `Files` services for clients to log in and message each other: what a
+//! user sends with `SendMessage` goes to the update streams of the peer, or of
+//! the other members of the group, and is kept for `GetDifference`. Files are
+//! uploaded and downloaded over plain HTTP at the URLs `Files` hands out.
+
+#![allow(clippy::result_large_err)]
+
+use bale::{
+    DeleteMessagesReply, DeleteMessagesRequest, FileLocation, GetDifferenceReply,
+    GetDifferenceRequest, GetFileUploadUrlReply, GetFileUploadUrlRequest, GetFileUrlReply,
+    GetFileUrlRequest, GetParametersReply, MavizDocumentMessage, MavizMessage, MavizPeer,
+    MavizPeerType, MavizTextMessage, MessagingPeer, MessagingPeerType, Profile,
+    ReceiveMessageRequest, SendMessageReply, SendMessageRequest, StartPhoneAuthReply,
+    StartPhoneAuthRequest, SubscribeToUpdatesReply, ValidateCodeReply, ValidateCodeRequest,
+};
+use bytes::Bytes;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use prost::Message;
+use std::collections::{HashMap, HashSet};
+use std::convert::{Infallible, TryInto};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
+use tonic::Status;
+use tracing::{debug, error};
+
+/// Code that validates every login.
+pub const LOGIN_CODE: &str = "12345";
+
+const FIRST_USER_ID: u32 = 1000;
+const FIRST_GROUP_ID: u32 = 5000;
+const FIRST_FILE_ID: i64 = 9000;
+// Path of the upload and download URLs, followed by the file id.
+const FILES_PATH: &str = "/files/";
+// Lifetime of the JWTs handed out.
+const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 3600);
+
+const HEADER_SIZE: usize = 5;
+const TRAILERS_FLAG: u8 = 0x80;
+const GRPC_WEB_PROTO: &str = "application/grpc-web+proto";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+/// Server running on a local port until it is dropped.
+pub struct MockBale {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    server: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct State {
+    // Base URL of the server.
+    url: String,
+    // User id of every registered phone number.
+    users: HashMap<u64, u32>,
+    // Phone number waiting for its code, by login hash.
+    logins: HashMap<String, u64>,
+    // User id by JWT.
+    sessions: HashMap<String, u32>,
+    // Members of each group.
+    groups: HashMap<u32, Vec<u32>>,
+    // Messages received by each user, oldest first.
+    inboxes: HashMap<u32, Vec<ReceiveMessageRequest>>,
+    // Open update streams of each user.
+    subscribers: HashMap<u32, Vec<UnboundedSender<ReceiveMessageRequest>>>,
+    // Methods that fail with an internal error.
+    failing: HashSet<String>,
+    files: HashMap<i64, StoredFile>,
+    last_timestamp: u64,
+}
+
+struct StoredFile {
+    access_hash: i64,
+    expected_size: i32,
+    // Empty until the file is uploaded.
+    content: Option<Bytes>,
+}
+
+impl MockBale {
+    /// Starts a server on a free port of 127.0.0.1. Must be called from a
+    /// Tokio runtime.
+    pub fn start() -> hyper::Result<MockBale> {
+        let incoming = AddrIncoming::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let addr = incoming.local_addr();
+        let state = Arc::new(Mutex::new(State {
+            url: format!("http://{}", addr),
+            ..State::default()
+        }));
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(&state, request).await) }
+                }))
+            }
+        });
+        let server = Server::builder(incoming).serve(make_service);
+        let server = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("mock server failed : {}", err);
+            }
+        });
+
+        Ok(MockBale {
+            addr,
+            state,
+            server,
+        })
+    }
+
+    /// Base URL to give to the gRPC-Web client.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Registers the phone number and returns the id of its user, so it can
+    /// log in and be messaged.
+    pub fn register(&self, phone_number: u64) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let user_id = FIRST_USER_ID + state.users.len() as u32;
+        *state.users.entry(phone_number).or_insert(user_id)
+    }
+
+    /// Creates a group of the users and returns its id.
+    pub fn create_group(&self, members: &[u32]) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let group_id = FIRST_GROUP_ID + state.groups.len() as u32;
+        state.groups.insert(group_id, members.to_vec());
+        group_id
+    }
+
+    /// Opens a session for the user without going through `Auth`, and returns
+    /// its JWT.
+    pub fn login(&self, user_id: u32) -> String {
+        self.state.lock().unwrap().login(user_id)
+    }
+
+    /// Forgets every session, so requests fail as unauthenticated until the
+    /// users log in again.
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().sessions.clear();
+    }
+
+    /// Makes every call of `method`, like `GetDifference`, fail.
+    pub fn fail(&self, method: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .failing
+            .insert(method.to_string());
+    }
+
+    /// Ends every update stream, as if the connections dropped.
+    pub fn close_update_streams(&self) {
+        self.state.lock().unwrap().subscribers.clear();
+    }
+
+    /// Whether the user has an update stream open.
+    pub fn is_subscribed(&self, user_id: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .get(&user_id)
+            .is_some_and(|subscribers| !subscribers.is_empty())
+    }
+
+    /// Number of files uploaded so far.
+    pub fn uploads(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .files
+            .values()
+            .filter(|file| file.content.is_some())
+            .count()
+    }
+
+    /// Messages received by the user and not deleted, oldest first.
+    pub fn inbox(&self, user_id: u32) -> Vec<ReceiveMessageRequest> {
+        let state = self.state.lock().unwrap();
+        state.inboxes.get(&user_id).cloned().unwrap_or_default()
+    }
+}
+
+impl Drop for MockBale {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl State {
+    fn user(&self, jwt: Option<&str>) -> Result<u32, Status> {
+        jwt.and_then(|jwt| self.sessions.get(jwt))
+            .copied()
+            .ok_or_else(|| Status::unauthenticated("invalid session"))
+    }
+
+    /// Users reading the chat `user_id` names `peer`, with the peer the chat
+    /// has in their updates.
+    fn readers(
+        &self,
+        user_id: u32,
+        peer: Option<MessagingPeer>,
+    ) -> Result<Vec<(u32, MavizPeer)>, Status> {
+        let peer = peer.unwrap_or_default();
+        let maviz_peer = |peer_type: MavizPeerType, id| MavizPeer {
+            r#type: peer_type as i32,
+            id,
+        };
+        match MessagingPeerType::from_i32(peer.r#type) {
+            Some(MessagingPeerType::Private) if self.users.values().any(|&id| id == peer.id) => {
+                Ok(vec![
+                    (peer.id, maviz_peer(MavizPeerType::Private, user_id)),
+                    (user_id, maviz_peer(MavizPeerType::Private, peer.id)),
+                ])
+            }
+            Some(MessagingPeerType::Group) => match self.groups.get(&peer.id) {
+                Some(members) if members.contains(&user_id) => Ok(members
+                    .iter()
+                    .map(|&member| (member, maviz_peer(MavizPeerType::Group, peer.id)))
+                    .collect()),
+                _ => Err(Status::not_found(format!("unknown group #{}", peer.id))),
+            },
+            _ => Err(Status::not_found(format!("unknown user #{}", peer.id))),
+        }
+    }
+
+    /// Milliseconds since the epoch, different for every message.
+    fn next_timestamp(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.last_timestamp = now.max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    fn login(&mut self, user_id: u32) -> String {
+        let issued_at = self.next_timestamp();
+        let claims = serde_json::json!({
+            "exp": issued_at + SESSION_LIFETIME.as_millis() as u64,
+            "iat": issued_at,
+            "iss": "bale-mock",
+            "payload": {
+                "app_id": 4,
+                "auth_id": issued_at.to_string(),
+                "auth_sid": 0,
+                "service": "web_lite",
+                "user_id": user_id,
+            },
+        });
+        let jwt = format!(
+            "{}.{}.",
+            base64::encode_config(r#"{"alg":"none"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+        );
+        self.sessions.insert(jwt.clone(), user_id);
+        jwt
+    }
+
+    fn start_phone_auth(&mut self, request: StartPhoneAuthRequest) -> StartPhoneAuthReply {
+        let login_hash = format!("{}-{}", request.phone_number, self.next_timestamp());
+        let registered = self.users.contains_key(&request.phone_number);
+        if registered {
+            self.logins.insert(login_hash.clone(), request.phone_number);
+        }
+        StartPhoneAuthReply {
+            login_hash,
+            registered: registered as i32,
+        }
+    }
+
+    fn validate_code(&mut self, request: ValidateCodeRequest) -> Result<ValidateCodeReply, Status> {
+        if request.login_code != LOGIN_CODE {
+            return Err(Status::invalid_argument("wrong login code"));
+        }
+        let user_id = self
+            .logins
+            .remove(&request.login_hash)
+            .and_then(|phone_number| self.users.get(&phone_number).copied())
+            .ok_or_else(|| Status::invalid_argument("unknown login hash"))?;
+        Ok(ValidateCodeReply {
+            profile: Some(Profile {
+                user_id,
+                display_name: format!("User #{}", user_id),
+            }),
+            auth: Some(bale::validate_code_reply::Auth {
+                jwt: self.login(user_id),
+            }),
+        })
+    }
+
+    fn send_message(
+        &mut self,
+        sender_id: u32,
+        request: SendMessageRequest,
+    ) -> Result<SendMessageReply, Status> {
+        let readers = self.readers(sender_id, request.peer)?;
+        let message = request.message.map(|message| MavizMessage {
+            text_message: message.text_message.map(|text_message| MavizTextMessage {
+                text: text_message.text,
+            }),
+            document_message: message
+                .document_message
+                .map(|document| MavizDocumentMessage {
+                    file_id: document.file_id,
+                    access_hash: document.access_hash,
+                    file_size: document.file_size,
+                    name: document.name,
+                    mime_type: document.mime_type,
+                }),
+        });
+        let timestamp = self.next_timestamp();
+        for (user_id, peer) in readers {
+            if user_id == sender_id {
+                continue;
+            }
+            let update = ReceiveMessageRequest {
+                peer: Some(peer),
+                sender_id,
+                timestamp,
+                rid: request.rid,
+                message: message.clone(),
+                unknown9: None,
+            };
+            debug!(
+                "user #{} sent {} to user #{}",
+                sender_id, request.rid, user_id
+            );
+
+            self.inboxes
+                .entry(user_id)
+                .or_default()
+                .push(update.clone());
+            if let Some(subscribers) = self.subscribers.get_mut(&user_id) {
+                subscribers.retain(|tx| tx.send(update.clone()).is_ok());
+            }
+        }
+        Ok(SendMessageReply {
+            unknown1: 0,
+            unknown2: timestamp,
+            key_values: Vec::new(),
+        })
+    }
+
+    fn delete_messages(
+        &mut self,
+        user_id: u32,
+        request: DeleteMessagesRequest,
+    ) -> Result<DeleteMessagesReply, Status> {
+        let rids = request.rids;
+        for (owner, peer) in self.readers(user_id, request.peer)? {
+            if request.just_for_me && owner != user_id {
+                continue;
+            }
+            if let Some(inbox) = self.inboxes.get_mut(&owner) {
+                inbox.retain(|update| {
+                    update.peer.as_ref() != Some(&peer) || !rids.contains(&update.rid)
+                });
+            }
+        }
+        Ok(DeleteMessagesReply {
+            seq: self.next_timestamp(),
+        })
+    }
+
+    fn get_file_upload_url(&mut self, request: GetFileUploadUrlRequest) -> GetFileUploadUrlReply {
+        let file_id = FIRST_FILE_ID + self.files.len() as i64;
+        let access_hash = self.next_timestamp() as i64;
+        self.files.insert(
+            file_id,
+            StoredFile {
+                access_hash,
+                expected_size: request.expected_size,
+                content: None,
+            },
+        );
+        GetFileUploadUrlReply {
+            file: Some(FileLocation {
+                file_id,
+                access_hash,
+            }),
+            url: self.file_url(file_id),
+        }
+    }
+
+    fn get_file_url(&self, request: GetFileUrlRequest) -> Result<GetFileUrlReply, Status> {
+        let location = request.file.unwrap_or_default();
+        match self.files.get(&location.file_id) {
+            Some(file) if file.access_hash == location.access_hash && file.content.is_some() => {
+                Ok(GetFileUrlReply {
+                    url: self.file_url(location.file_id),
+                })
+            }
+            _ => Err(Status::not_found(format!(
+                "unknown file #{}",
+                location.file_id
+            ))),
+        }
+    }
+
+    fn file_url(&self, file_id: i64) -> String {
+        format!("{}{}{}", self.url, FILES_PATH, file_id)
+    }
+
+    fn get_difference(&self, user_id: u32, request: GetDifferenceRequest) -> GetDifferenceReply {
+        let mut updates: Vec<ReceiveMessageRequest> = self
+            .inboxes
+            .get(&user_id)
+            .map(|inbox| inbox.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|update| update.timestamp > request.timestamp)
+            .cloned()
+            .collect();
+        let limit = request.limit as usize;
+        let need_more = limit > 0 && updates.len() > limit;
+        if need_more {
+            updates.truncate(limit);
+        }
+        GetDifferenceReply { updates, need_more }
+    }
+}
+
+async fn handle(state: &Mutex<State>, request: Request<Body>) -> Response<Body> {
+    if request.uri().path().starts_with(FILES_PATH) {
+        return transfer(state, request).await;
+    }
+    match call(state, request).await {
+        Ok(response) => response,
+        Err(status) => {
+            debug!("replying with {:?}", status);
+            // Trailers-only response, the client reads the status from the
+            // headers.
+            Response::builder()
+                .header(header::CONTENT_TYPE, GRPC_WEB_PROTO)
+                .header("grpc-status", status.code() as i32)
+                .header("grpc-message", status.message())
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+}
+
+/// Stores an upload or serves a download. Files are uploaded once, with the
+/// size announced when asking for the upload URL.
+async fn transfer(state: &Mutex<State>, request: Request<Body>) -> Response<Body> {
+    let file_id = request.uri().path()[FILES_PATH.len()..].parse::<i64>().ok();
+    let method = request.method().clone();
+    let body = hyper::body::to_bytes(request.into_body()).await;
+    debug!("{} file {:?}", method, file_id);
+
+    let mut state = state.lock().unwrap();
+    let file = file_id.and_then(|file_id| state.files.get_mut(&file_id));
+    let status = match (method, file, body) {
+        (_, None, _) => StatusCode::NOT_FOUND,
+        (Method::PUT, Some(file), Ok(body)) => {
+            if file.content.is_some() {
+                StatusCode::CONFLICT
+            } else if body.len() != file.expected_size as usize {
+                StatusCode::BAD_REQUEST
+            } else {
+                file.content = Some(body);
+                StatusCode::OK
+            }
+        }
+        (Method::PUT, Some(_), Err(_)) => StatusCode::BAD_REQUEST,
+        (Method::GET, Some(file), _) => match &file.content {
+            Some(content) => return Response::new(Body::from(content.clone())),
+            None => StatusCode::NOT_FOUND,
+        },
+        _ => StatusCode::METHOD_NOT_ALLOWED,
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn call(state: &Mutex<State>, request: Request<Body>) -> Result<Response<Body>, Status> {
+    let text = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(GRPC_WEB_TEXT));
+    let jwt = request
+        .headers()
+        .get("auth-jwt")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let jwt = jwt.as_deref();
+    let path = request.uri().path().to_string();
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?;
+    let message = read_message(body, text)?;
+    debug!("{}", path);
+
+    let mut state = state.lock().unwrap();
+    let method = path.rsplit('/').next().unwrap_or_default();
+    if state.failing.contains(method) {
+        return Err(Status::internal(format!("{} is failing", method)));
+    }
+    match path.as_str() {
+        "/bale.auth.v1.Auth/StartPhoneAuth" => Ok(reply(&state.start_phone_auth(decode(message)?))),
+        "/bale.auth.v1.Auth/ValidateCode" => Ok(reply(&state.validate_code(decode(message)?)?)),
+        "/bale.v1.Configs/GetParameters" => {
+            state.user(jwt)?;
+            Ok(reply(&GetParametersReply {
+                configs: Vec::new(),
+            }))
+        }
+        "/bale.messaging.v2.Messaging/SendMessage" => {
+            let user_id = state.user(jwt)?;
+            Ok(reply(&state.send_message(user_id, decode(message)?)?))
+        }
+        "/bale.messaging.v2.Messaging/DeleteMessages" => {
+            let user_id = state.user(jwt)?;
+            Ok(reply(&state.delete_messages(user_id, decode(message)?)?))
+        }
+        "/bale.files.v1.Files/GetFileUploadUrl" => {
+            state.user(jwt)?;
+            Ok(reply(&state.get_file_upload_url(decode(message)?)))
+        }
+        "/bale.files.v1.Files/GetFileUrl" => {
+            state.user(jwt)?;
+            Ok(reply(&state.get_file_url(decode(message)?)?))
+        }
+        "/bale.maviz.v1.MavizStream/GetDifference" => {
+            let user_id = state.user(jwt)?;
+            Ok(reply(&state.get_difference(user_id, decode(message)?)))
+        }
+        "/bale.maviz.v1.MavizStream/SubscribeToUpdates" => {
+            let user_id = state.user(jwt)?;
+            let (tx, mut rx) = unbounded_channel::<ReceiveMessageRequest>();
+            state.subscribers.entry(user_id).or_default().push(tx);
+
+            // The stream lasts until the client goes away, which is only
+            // noticed on the next update.
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                while let Some(update) = rx.recv().await {
+                    let timestamp = update.timestamp;
+                    let update = SubscribeToUpdatesReply {
+                        request: Some(bale::Request {
+                            receive_message: Some(update),
+                        }),
+                        send_timestamp: timestamp,
+                        unknown3: 0,
+                        receive_timestamp: timestamp,
+                    };
+                    if sender.send_data(data_frame(&update).into()).await.is_err() {
+                        break;
+                    }
+                }
+                debug!("update stream of user #{} closed", user_id);
+            });
+            Ok(response(body))
+        }
+        _ => Err(Status::unimplemented(path)),
+    }
+}
+
+/// Takes the message out of a request body with a single data frame.
+fn read_message(body: Bytes, text: bool) -> Result<Bytes, Status> {
+    let body = if text {
+        base64::decode(&body)
+            .map_err(|_| Status::invalid_argument("malformed base64 body"))?
+            .into()
+    } else {
+        body
+    };
+    if body.len() < HEADER_SIZE || body[0] != 0 {
+        return Err(Status::invalid_argument("malformed request frame"));
+    }
+    let length = u32::from_be_bytes(body[1..HEADER_SIZE].try_into().unwrap()) as usize;
+    if body.len() != HEADER_SIZE + length {
+        return Err(Status::invalid_argument("malformed request frame"));
+    }
+    Ok(body.slice(HEADER_SIZE..))
+}
+
+fn decode<M: Message + Default>(message: Bytes) -> Result<M, Status> {
+    M::decode(message).map_err(|err| Status::invalid_argument(err.to_string()))
+}
+
+fn data_frame<M: Message>(message: &M) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + message.encoded_len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.encoded_len() as u32).to_be_bytes());
+    message.encode(&mut frame).unwrap();
+    frame
+}
+
+/// Response with a single message, also used for server streams that only
+/// send one.
+fn reply<M: Message>(message: &M) -> Response<Body> {
+    let trailers = b"grpc-status:0\r\n";
+    let mut body = data_frame(message);
+    body.push(TRAILERS_FLAG);
+    body.extend_from_slice(&(trailers.len() as u32).to_be_bytes());
+    body.extend_from_slice(trailers);
+    response(Body::from(body))
+}
+
+/// Replies are always binary, which the client reads whatever it asked for.
+fn response(body: Body) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, GRPC_WEB_PROTO)
+        .body(body)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bale::auth_client::AuthClient;
+    use bale::maviz_stream_client::MavizStreamClient;
+    use bale::messaging_client::MessagingClient;
+    use bale::{
+        BaleClient, LoginStatus, MessagingMessage, MessagingTextMessage, Peer,
+        SubscribeToUpdatesRequest,
+    };
+    use grpc_web_client::{Client, Encoding};
+
+    fn authorize<T>(message: T, jwt: &str) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("auth-jwt", jwt.parse().unwrap());
+        request
+    }
+
+    fn text(peer: Peer, rid: u64, text: &str) -> SendMessageRequest {
+        SendMessageRequest {
+            peer: Some(peer.into()),
+            rid,
+            message: Some(MessagingMessage {
+                text_message: Some(MessagingTextMessage {
+                    text: text.to_string(),
+                }),
+                document_message: None,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_messages_to_the_peer() {
+        let bale = MockBale::start().unwrap();
+        let alice = bale.register(989_120_000_001);
+        let bob = bale.register(989_120_000_002);
+        let client = Client::new_with_encoding(bale.url(), Encoding::Base64);
+
+        let mut auth = AuthClient::new(client.clone());
+        let login = auth
+            .start_phone_auth(tonic::Request::new(StartPhoneAuthRequest {
+                phone_number: 989_120_000_002,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(login.registered, 1);
+        let validated = auth
+            .validate_code(tonic::Request::new(ValidateCodeRequest {
+                login_hash: login.login_hash,
+                login_code: LOGIN_CODE.to_string(),
+                validate_code_request_sub_request: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(validated.profile.unwrap().user_id, bob);
+        let bob_jwt = validated.auth.unwrap().jwt;
+
+        let mut updates = MavizStreamClient::new(client.clone())
+            .subscribe_to_updates(authorize(SubscribeToUpdatesRequest {}, &bob_jwt))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut messaging = MessagingClient::new(client.clone());
+        for rid in 1..=2 {
+            messaging
+                .send_message(authorize(
+                    text(Peer::User(bob), rid, "hello"),
+                    &bale.login(alice),
+                ))
+                .await
+                .unwrap();
+        }
+
+        for rid in 1..=2 {
+            let update = updates.message().await.unwrap().unwrap();
+            let update = update.request.unwrap().receive_message.unwrap();
+            assert_eq!((update.sender_id, update.rid), (alice, rid));
+            assert_eq!(update.message.unwrap().text_message.unwrap().text, "hello");
+        }
+
+        let difference = MavizStreamClient::new(client)
+            .get_difference(authorize(
+                GetDifferenceRequest {
+                    timestamp: 0,
+                    limit: 1,
+                },
+                &bob_jwt,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(difference.updates[0].rid, 1);
+        assert!(difference.need_more);
+        assert_eq!(bale.inbox(bob).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_sessions_and_peers() {
+        let bale = MockBale::start().unwrap();
+        let alice = bale.register(989_120_000_001);
+        let jwt = bale.login(alice);
+        let mut messaging = MessagingClient::new(Client::new(bale.url()));
+
+        let status = messaging
+            .send_message(authorize(text(Peer::User(42), 1, "hello"), &jwt))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        bale.expire_sessions();
+        let status = messaging
+            .send_message(authorize(text(Peer::User(alice), 1, "hello"), &jwt))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn serves_bale_clients() {
+        let bale = MockBale::start().unwrap();
+        let alice_id = bale.register(989_120_000_001);
+        let bob_id = bale.register(989_120_000_002);
+
+        let alice = BaleClient::builder(989_120_000_001)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        alice.login().await.unwrap();
+        assert_eq!(
+            alice.validate_code(LOGIN_CODE).await.unwrap().user_id,
+            alice_id
+        );
+
+        let bob = BaleClient::builder(989_120_000_002)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        let status = bob.login_with(bale.login(bob_id)).await.unwrap();
+        assert!(matches!(status, LoginStatus::LoggedIn(_, user_id) if user_id == bob_id));
+        let (tx, rx) = async_std::channel::unbounded();
+        tokio::spawn(async move { bob.subscribe_to_updates(tx).await });
+        while !bale.is_subscribed(bob_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        alice
+            .send_message(Peer::User(bob_id), "hello".to_string())
+            .await
+            .unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!(received.sender_id, alice_id);
+        assert_eq!(received.peer, Peer::User(alice_id));
+        assert!(matches!(received.message, bale::Message::Text(text) if text == "hello"));
+
+        let group = Peer::Group(bale.create_group(&[alice_id, bob_id]));
+        alice
+            .send_message(group, "hello all".to_string())
+            .await
+            .unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!((received.peer, received.sender_id), (group, alice_id));
+    }
+
+    #[tokio::test]
+    async fn transfers_documents() {
+        let bale = MockBale::start().unwrap();
+        let alice_id = bale.register(989_120_000_001);
+        let bob_id = bale.register(989_120_000_002);
+        let alice = BaleClient::builder(989_120_000_001)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        alice.login_with(bale.login(alice_id)).await.unwrap();
+        let bob = BaleClient::builder(989_120_000_002)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        bob.login_with(bale.login(bob_id)).await.unwrap();
+
+        let content: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        alice
+            .send_document(Peer::User(bob_id), "data.bin".to_string(), content.clone())
+            .await
+            .unwrap();
+
+        let update = bale.inbox(bob_id).remove(0);
+        let document = update.message.unwrap().document_message.unwrap();
+        assert_eq!(document.file_size, content.len() as i32);
+        let document = bale::Document {
+            file_id: document.file_id,
+            access_hash: document.access_hash,
+            size: document.file_size,
+            name: document.name,
+            mime_type: document.mime_type,
+        };
+        assert_eq!(bob.download_file(&document).await.unwrap(), content);
+
+        let forged = bale::Document {
+            access_hash: document.access_hash + 1,
+            ..document
+        };
+        assert!(bob.download_file(&forged).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn delivers_group_messages_to_other_members() {
+        let bale = MockBale::start().unwrap();
+        let alice = bale.register(989_120_000_001);
+        let bob = bale.register(989_120_000_002);
+        let carol = bale.register(989_120_000_003);
+        let group = bale.create_group(&[alice, bob]);
+        let mut messaging = MessagingClient::new(Client::new(bale.url()));
+
+        messaging
+            .send_message(authorize(
+                text(Peer::Group(group), 1, "hello"),
+                &bale.login(alice),
+            ))
+            .await
+            .unwrap();
+        assert!(bale.inbox(alice).is_empty());
+        let update = &bale.inbox(bob)[0];
+        assert_eq!(update.sender_id, alice);
+        assert_eq!(
+            update.peer,
+            Some(MavizPeer {
+                r#type: MavizPeerType::Group as i32,
+                id: group,
+            })
+        );
+
+        let status = messaging
+            .send_message(authorize(
+                text(Peer::Group(group), 2, "hello"),
+                &bale.login(carol),
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn keeps_streaming_when_catching_up_fails() {
+        let bale = MockBale::start().unwrap();
+        let alice_id = bale.register(989_120_000_001);
+        let bob_id = bale.register(989_120_000_002);
+        let alice = BaleClient::builder(989_120_000_001)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        alice.login_with(bale.login(alice_id)).await.unwrap();
+        let bob = BaleClient::builder(989_120_000_002)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        bob.login_with(bale.login(bob_id)).await.unwrap();
+        let (tx, rx) = async_std::channel::unbounded();
+        tokio::spawn(async move { bob.subscribe_to_updates(tx).await });
+
+        let hello = |text: &str| alice.send_message(Peer::User(bob_id), text.to_string());
+        while !bale.is_subscribed(bob_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        hello("first").await.unwrap();
+        rx.recv().await.unwrap();
+
+        bale.fail("GetDifference");
+        bale.close_update_streams();
+        while !bale.is_subscribed(bob_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        hello("second").await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .expect("update stream stopped")
+            .unwrap();
+        assert!(matches!(received.message, bale::Message::Text(text) if text == "second"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::convert::TryFrom;
+
+use crate::error::Error;
+
+/// Turns packets into text that can be sent as a messenger message, and back.
+pub(crate) trait PayloadCodec: Send + Sync {
+    /// Name used to pick the codec in plugin options and during the handshake.
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, bytes: &[u8]) -> String;
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, Error>;
+
+    /// Number of bytes that always fit in `chars` characters once encoded.
+    fn capacity(&self, chars: usize) -> usize;
+}
+
+/// Codecs this build supports, the preferred ones first.
+pub(crate) const CODECS: &[&dyn PayloadCodec] = &[&Base32768, &Base64];
+
+pub(crate) fn codec_by_name(name: &str) -> Option<&'static dyn PayloadCodec> {
+    CODECS
+        .iter()
+        .copied()
+        .find(|codec| codec.name().eq_ignore_ascii_case(name))
+}
+
+/// Plain base64, 6 bits per character. Handshake packets always use it.
+pub(crate) struct Base64;
+
+impl PayloadCodec for Base64 {
+    fn name(&self) -> &'static str {
+        "base64"
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        base64::encode(bytes)
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        Ok(base64::decode(text)?)
+    }
+
+    fn capacity(&self, chars: usize) -> usize {
+        chars / 4 * 3
+    }
+}
+
+/// Maps every 15 bits to one CJK ideograph or Hangul syllable, so a character
+/// carries 15 bits instead of the 6 bits of base64.
+///
+/// If the last chunk has 7 bits or less, it is mapped to a separate range of
+/// 128 characters, so the decoder can tell padding from data.
+pub(crate) struct Base32768;
+
+// (first code point, number of code points) of the ranges used for 15 bit chunks.
+const BLOCKS: &[(u32, u32)] = &[
+    (0x4E00, 20992), // CJK Unified Ideographs
+    (0xAC00, 11172), // Hangul Syllables
+    (0x3400, 604),   // start of CJK Unified Ideographs Extension A
+];
+// Rest of CJK Unified Ideographs Extension A, used for the last 7 bit chunk.
+const TAIL_START: u32 = 0x3400 + 604;
+
+impl Base32768 {
+    fn chunk_to_char(mut value: u32) -> char {
+        for &(start, len) in BLOCKS {
+            if value < len {
+                return char::try_from(start + value).unwrap();
+            }
+            value -= len;
+        }
+        unreachable!("chunks are 15 bits long")
+    }
+
+    /// Returns the value and number of bits of the chunk mapped to `c`.
+    fn char_to_chunk(c: char) -> Option<(u32, u32)> {
+        let code = c as u32;
+        let mut offset = 0;
+        for &(start, len) in BLOCKS {
+            if (start..start + len).contains(&code) {
+                return Some((offset + code - start, 15));
+            }
+            offset += len;
+        }
+        if (TAIL_START..TAIL_START + 128).contains(&code) {
+            return Some((code - TAIL_START, 7));
+        }
+        None
+    }
+}
+
+impl PayloadCodec for Base32768 {
+    fn name(&self) -> &'static str {
+        "base32768"
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        let mut text = String::with_capacity((bytes.len() * 8).div_ceil(15) * 3);
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+
+        for &byte in bytes {
+            acc = (acc << 8) | byte as u32;
+            bits += 8;
+            if bits >= 15 {
+                bits -= 15;
+                text.push(Self::chunk_to_char((acc >> bits) & 0x7FFF));
+                acc &= (1 << bits) - 1;
+            }
+        }
+
+        if bits > 7 {
+            text.push(Self::chunk_to_char((acc << (15 - bits)) & 0x7FFF));
+        } else if bits > 0 {
+            text.push(char::try_from(TAIL_START + ((acc << (7 - bits)) & 0x7F)).unwrap());
+        }
+        text
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::with_capacity(text.len() / 3 * 15 / 8 + 1);
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+        let mut finished = false;
+
+        for c in text.chars() {
+            let (value, size) = Self::char_to_chunk(c).ok_or_else(|| {
+                Error::InvalidFrame(format!("invalid base32768 character {:?}", c))
+            })?;
+            if finished {
+                return Err(Error::InvalidFrame(
+                    "base32768 text continues after its last chunk".to_string(),
+                ));
+            }
+            finished = size < 15;
+
+            acc = (acc << size) | value;
+            bits += size;
+            while bits >= 8 {
+                bits -= 8;
+                bytes.push((acc >> bits) as u8);
+            }
+            acc &= (1 << bits) - 1;
+        }
+        Ok(bytes)
+    }
+
+    fn capacity(&self, chars: usize) -> usize {
+        chars * 15 / 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codecs_round_trip() {
+        for codec in CODECS {
+            for len in 0..64 {
+                let bytes: Vec<u8> = (0..len).map(|i| (i * 37 + 11) as u8).collect();
+                let text = codec.encode(&bytes);
+                assert_eq!(codec.decode(&text).unwrap(), bytes, "{}", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn base32768_uses_every_chunk_value() {
+        for value in 0..0x8000 {
+            let c = Base32768::chunk_to_char(value);
+            assert_eq!(Base32768::char_to_chunk(c), Some((value, 15)));
+        }
+    }
+
+    #[test]
+    fn capacity_fits_in_characters() {
+        for codec in CODECS {
+            for chars in (0..200).step_by(7) {
+                let bytes = vec![0xA5; codec.capacity(chars)];
+                assert!(codec.encode(&bytes).chars().count() <= chars);
+            }
+        }
+    }
+
+    #[test]
+    fn base32768_rejects_foreign_text() {
+        assert!(Base32768.decode("aGVsbG8=").is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// Settings read from the file given with the `config=` plugin option.
+///
+/// ```json
+/// {
+///   "server": {
+///     "allowed_users": [932014429, 703729447]
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    pub(crate) server: ServerConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct ServerConfig {
+    /// Bale user ids that are allowed to use the tunnel.
+    #[serde(default)]
+    pub(crate) allowed_users: Vec<u32>,
+}
+
+impl Config {
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
@@ -0,0 +1,455 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use x25519_dalek::{EphemeralSecret, PublicKey, ReusableSecret, StaticSecret};
+
+use crate::error::Error;
+
+pub(crate) const PACKET_CLIENT_HELLO: u8 = 1;
+pub(crate) const PACKET_SERVER_HELLO: u8 = 2;
+pub(crate) const PACKET_SEALED: u8 = 3;
+
+const KEY_SIZE: usize = 32;
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+const REPLAY_WINDOW: u64 = 64;
+
+/// Number of bytes sealing adds to a packet.
+pub(crate) const SEAL_OVERHEAD: usize = 1 + COUNTER_SIZE + TAG_SIZE;
+
+const HANDSHAKE_INFO: &[u8] = b"bale-proxy handshake v1";
+
+/// Packet carried in a single messenger message, before it is encoded to text.
+///
+/// The client hello carries the client ephemeral key and the client options
+/// in clear. The server hello carries the server ephemeral key and the sealed
+/// server options, which also prove the server holds the pinned key.
+pub(crate) enum Packet<'a> {
+    ClientHello(PublicKey, &'a [u8]),
+    ServerHello(PublicKey, &'a [u8]),
+    Sealed(&'a [u8]),
+}
+
+impl<'a> Packet<'a> {
+    pub(crate) fn parse(bytes: &'a [u8]) -> Result<Packet<'a>, Error> {
+        match bytes.split_first() {
+            Some((&PACKET_CLIENT_HELLO, body)) => Ok(Packet::ClientHello(
+                read_public_key(body)?,
+                &body[KEY_SIZE..],
+            )),
+            Some((&PACKET_SERVER_HELLO, body)) => Ok(Packet::ServerHello(
+                read_public_key(body)?,
+                &body[KEY_SIZE..],
+            )),
+            Some((&PACKET_SEALED, _)) => Ok(Packet::Sealed(bytes)),
+            Some((kind, _)) => Err(Error::CryptoError(format!("unknown packet kind {}", kind))),
+            None => Err(Error::CryptoError("empty packet".to_string())),
+        }
+    }
+}
+
+fn read_public_key(bytes: &[u8]) -> Result<PublicKey, Error> {
+    if bytes.len() < KEY_SIZE {
+        return Err(Error::CryptoError("public key is too short".to_string()));
+    }
+    let key: [u8; KEY_SIZE] = bytes[..KEY_SIZE].try_into().unwrap();
+    Ok(PublicKey::from(key))
+}
+
+pub(crate) fn generate_static_key() -> StaticSecret {
+    StaticSecret::random_from_rng(OsRng)
+}
+
+/// Reads the private key saved in `path`, or generates one and saves it there,
+/// only readable by its owner. Returns whether the key is new.
+pub(crate) fn load_or_create_static_key(path: &Path) -> Result<(StaticSecret, bool), Error> {
+    if let Ok(encoded) = fs::read_to_string(path) {
+        return Ok((decode_static_key(encoded.trim())?, false));
+    }
+
+    let static_key = generate_static_key();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(base64::encode(static_key.to_bytes()).as_bytes())?;
+    Ok((static_key, true))
+}
+
+pub(crate) fn decode_static_key(encoded: &str) -> Result<StaticSecret, Error> {
+    let bytes: [u8; KEY_SIZE] = base64::decode(encoded)?
+        .try_into()
+        .map_err(|_| Error::CryptoError("private key must be 32 bytes".to_string()))?;
+    Ok(StaticSecret::from(bytes))
+}
+
+pub(crate) fn decode_public_key(encoded: &str) -> Result<PublicKey, Error> {
+    read_public_key(&base64::decode(encoded)?)
+}
+
+/// Client side of the handshake, waiting for the server hello.
+///
+/// The client mixes its ephemeral key with both the pinned static key and the
+/// ephemeral key of the server, so only the holder of the static private key
+/// can derive the session keys and answer the hello. The options sent in the
+/// clear are mixed into the keys as well, so they cannot be rewritten on the
+/// way.
+pub(crate) struct ClientHandshake {
+    ephemeral: ReusableSecret,
+    server_static: PublicKey,
+    options: Vec<u8>,
+}
+
+impl ClientHandshake {
+    pub(crate) fn start(server_static: PublicKey, options: &[u8]) -> (ClientHandshake, Vec<u8>) {
+        let ephemeral = ReusableSecret::random_from_rng(OsRng);
+
+        let mut hello = vec![PACKET_CLIENT_HELLO];
+        hello.extend_from_slice(PublicKey::from(&ephemeral).as_bytes());
+        hello.extend_from_slice(options);
+
+        (
+            ClientHandshake {
+                ephemeral,
+                server_static,
+                options: options.to_vec(),
+            },
+            hello,
+        )
+    }
+
+    /// Returns the session cipher and the options the server sent.
+    pub(crate) fn finish(
+        &self,
+        server_ephemeral: PublicKey,
+        confirmation: &[u8],
+    ) -> Result<(Cipher, Vec<u8>), Error> {
+        let static_shared = self.ephemeral.diffie_hellman(&self.server_static);
+        let ephemeral_shared = self.ephemeral.diffie_hellman(&server_ephemeral);
+
+        let (client_key, server_key) = derive_keys(
+            static_shared.as_bytes(),
+            ephemeral_shared.as_bytes(),
+            &PublicKey::from(&self.ephemeral),
+            &server_ephemeral,
+            &self.server_static,
+            &self.options,
+        );
+
+        let mut cipher = Cipher::new(client_key, server_key);
+        let options = cipher
+            .open(confirmation)
+            .map_err(|_| Error::CryptoError("server could not prove its identity".to_string()))?;
+        Ok((cipher, options))
+    }
+}
+
+/// Server side of the handshake, `client_options` being the options of the
+/// client hello as received. Returns the session cipher and the server hello.
+pub(crate) fn accept(
+    server_static: &StaticSecret,
+    client_ephemeral: PublicKey,
+    client_options: &[u8],
+    options: &[u8],
+) -> (Cipher, Vec<u8>) {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let server_ephemeral = PublicKey::from(&ephemeral);
+
+    let static_shared = server_static.diffie_hellman(&client_ephemeral);
+    let ephemeral_shared = ephemeral.diffie_hellman(&client_ephemeral);
+
+    let (client_key, server_key) = derive_keys(
+        static_shared.as_bytes(),
+        ephemeral_shared.as_bytes(),
+        &client_ephemeral,
+        &server_ephemeral,
+        &PublicKey::from(server_static),
+        client_options,
+    );
+
+    let mut cipher = Cipher::new(server_key, client_key);
+
+    let mut hello = vec![PACKET_SERVER_HELLO];
+    hello.extend_from_slice(server_ephemeral.as_bytes());
+    hello.extend(cipher.seal(options));
+
+    (cipher, hello)
+}
+
+fn derive_keys(
+    static_shared: &[u8],
+    ephemeral_shared: &[u8],
+    client_ephemeral: &PublicKey,
+    server_ephemeral: &PublicKey,
+    server_static: &PublicKey,
+    client_options: &[u8],
+) -> ([u8; KEY_SIZE], [u8; KEY_SIZE]) {
+    let mut transcript = Vec::with_capacity(3 * KEY_SIZE + client_options.len());
+    transcript.extend_from_slice(client_ephemeral.as_bytes());
+    transcript.extend_from_slice(server_ephemeral.as_bytes());
+    transcript.extend_from_slice(server_static.as_bytes());
+    transcript.extend_from_slice(client_options);
+
+    let mut shared = Vec::with_capacity(2 * KEY_SIZE);
+    shared.extend_from_slice(static_shared);
+    shared.extend_from_slice(ephemeral_shared);
+
+    let mut okm = [0u8; 2 * KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&transcript), &shared)
+        .expand(HANDSHAKE_INFO, &mut okm)
+        .expect("64 bytes is a valid length for HKDF-SHA256");
+
+    (
+        okm[..KEY_SIZE].try_into().unwrap(),
+        okm[KEY_SIZE..].try_into().unwrap(),
+    )
+}
+
+/// Seals and opens the packets of an established session.
+///
+/// Each direction has its own key and the nonce is a per-direction counter
+/// that is sent along with the ciphertext, so packets can be opened even if
+/// the messenger delivers them out of order. Already seen counters are
+/// rejected to stop replayed packets.
+pub(crate) struct Cipher {
+    sealing: ChaCha20Poly1305,
+    opening: ChaCha20Poly1305,
+    send_counter: u64,
+    highest_received: Option<u64>,
+    received_window: u64,
+}
+
+impl Cipher {
+    fn new(sealing_key: [u8; KEY_SIZE], opening_key: [u8; KEY_SIZE]) -> Cipher {
+        Cipher {
+            sealing: ChaCha20Poly1305::new(Key::from_slice(&sealing_key)),
+            opening: ChaCha20Poly1305::new(Key::from_slice(&opening_key)),
+            send_counter: 0,
+            highest_received: None,
+            received_window: 0,
+        }
+    }
+
+    pub(crate) fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let mut packet = Vec::with_capacity(SEAL_OVERHEAD + plaintext.len());
+        packet.push(PACKET_SEALED);
+        packet.extend_from_slice(&counter.to_be_bytes());
+
+        let ciphertext = self
+            .sealing
+            .encrypt(
+                &nonce(counter),
+                Payload {
+                    msg: plaintext,
+                    aad: &packet,
+                },
+            )
+            .expect("sealing a packet can not fail");
+        packet.extend(ciphertext);
+        packet
+    }
+
+    pub(crate) fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, Error> {
+        let header_size = 1 + COUNTER_SIZE;
+        if packet.len() < header_size + TAG_SIZE || packet[0] != PACKET_SEALED {
+            return Err(Error::CryptoError("malformed sealed packet".to_string()));
+        }
+
+        let counter = u64::from_be_bytes(packet[1..header_size].try_into().unwrap());
+        if self.is_replayed(counter) {
+            return Err(Error::CryptoError(format!(
+                "packet #{} is replayed or too old",
+                counter
+            )));
+        }
+
+        let plaintext = self
+            .opening
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: &packet[header_size..],
+                    aad: &packet[..header_size],
+                },
+            )
+            .map_err(|_| Error::CryptoError(format!("packet #{} is forged", counter)))?;
+
+        self.mark_received(counter);
+        Ok(plaintext)
+    }
+
+    fn is_replayed(&self, counter: u64) -> bool {
+        match self.highest_received {
+            None => false,
+            Some(highest) if counter > highest => false,
+            Some(highest) => {
+                let age = highest - counter;
+                age >= REPLAY_WINDOW || self.received_window & (1 << age) != 0
+            }
+        }
+    }
+
+    fn mark_received(&mut self, counter: u64) {
+        match self.highest_received {
+            Some(highest) if counter <= highest => {
+                self.received_window |= 1 << (highest - counter);
+            }
+            Some(highest) => {
+                let shift = counter - highest;
+                self.received_window = if shift >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.received_window << shift) | 1
+                };
+                self.highest_received = Some(counter);
+            }
+            None => {
+                self.received_window = 1;
+                self.highest_received = Some(counter);
+            }
+        }
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> (Cipher, Cipher) {
+        let server_static = generate_static_key();
+        let (client, client_hello) =
+            ClientHandshake::start(PublicKey::from(&server_static), b"client options");
+
+        let client_ephemeral = match Packet::parse(&client_hello).unwrap() {
+            Packet::ClientHello(key, options) => {
+                assert_eq!(options, b"client options");
+                key
+            }
+            _ => panic!("expected client hello"),
+        };
+        let (server_cipher, server_hello) = accept(
+            &server_static,
+            client_ephemeral,
+            b"client options",
+            b"server options",
+        );
+
+        let (client_cipher, options) = match Packet::parse(&server_hello).unwrap() {
+            Packet::ServerHello(key, confirmation) => client.finish(key, confirmation).unwrap(),
+            _ => panic!("expected server hello"),
+        };
+        assert_eq!(options, b"server options");
+        (client_cipher, server_cipher)
+    }
+
+    #[test]
+    fn sealed_packets_round_trip_both_ways() {
+        let (mut client, mut server) = handshake();
+
+        let packet = client.seal(b"ping");
+        assert_eq!(server.open(&packet).unwrap(), b"ping");
+
+        let packet = server.seal(b"pong");
+        assert_eq!(client.open(&packet).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn rejects_impersonated_server() {
+        let pinned = generate_static_key();
+        let impostor = generate_static_key();
+        let (client, client_hello) = ClientHandshake::start(PublicKey::from(&pinned), &[]);
+
+        let client_ephemeral = match Packet::parse(&client_hello).unwrap() {
+            Packet::ClientHello(key, _) => key,
+            _ => panic!("expected client hello"),
+        };
+        let (_, server_hello) = accept(&impostor, client_ephemeral, &[], &[]);
+
+        match Packet::parse(&server_hello).unwrap() {
+            Packet::ServerHello(key, confirmation) => {
+                assert!(client.finish(key, confirmation).is_err())
+            }
+            _ => panic!("expected server hello"),
+        }
+    }
+
+    #[test]
+    fn rejects_rewritten_client_options() {
+        let server_static = generate_static_key();
+        let (client, client_hello) =
+            ClientHandshake::start(PublicKey::from(&server_static), b"base32768,base64");
+
+        let client_ephemeral = match Packet::parse(&client_hello).unwrap() {
+            Packet::ClientHello(key, _) => key,
+            _ => panic!("expected client hello"),
+        };
+        let (_, server_hello) = accept(&server_static, client_ephemeral, b"base64", b"base64");
+
+        match Packet::parse(&server_hello).unwrap() {
+            Packet::ServerHello(key, confirmation) => {
+                assert!(client.finish(key, confirmation).is_err())
+            }
+            _ => panic!("expected server hello"),
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_and_replayed_packets() {
+        let (mut client, mut server) = handshake();
+
+        let first = client.seal(b"first");
+        let second = client.seal(b"second");
+
+        let mut tampered = second.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(server.open(&tampered).is_err());
+
+        // Out of order delivery is fine, replays are not.
+        assert_eq!(server.open(&second).unwrap(), b"second");
+        assert_eq!(server.open(&first).unwrap(), b"first");
+        assert!(server.open(&first).is_err());
+        assert!(server.open(&second).is_err());
+    }
+
+    #[test]
+    fn saves_generated_keys_privately() {
+        let dir = std::env::temp_dir().join(format!("bale-proxy-{}", rand::random::<u64>()));
+        let path = dir.join("1.key");
+
+        let (static_key, created) = load_or_create_static_key(&path).unwrap();
+        assert!(created);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let (loaded, created) = load_or_create_static_key(&path).unwrap();
+        assert!(!created);
+        assert_eq!(loaded.to_bytes(), static_key.to_bytes());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
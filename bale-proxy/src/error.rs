@@ -1,11 +1,21 @@
 use std::fmt::{self, Display, Formatter};
 
+use crate::transport::Chat;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     InternalError(String),
     ServerError(String),
+    InvalidFrame(String),
+    CryptoError(String),
+    InvalidSettings(String),
+    UnknownChat(Chat),
+    BaleError(bale::Error),
     ChannelRecvErr(async_std::channel::RecvError),
     ParseError(url::ParseError),
+    DecodeError(base64::DecodeError),
+    JsonError(serde_json::Error),
     ParseIntError(std::num::ParseIntError),
     ParseUtf8Error(std::str::Utf8Error),
     FromUtf8Error(std::string::FromUtf8Error),
@@ -17,8 +27,15 @@ impl Display for Error {
         match *self {
             Error::InternalError(ref e) => e.fmt(f),
             Error::ServerError(ref e) => e.fmt(f),
+            Error::InvalidFrame(ref e) => e.fmt(f),
+            Error::CryptoError(ref e) => e.fmt(f),
+            Error::InvalidSettings(ref e) => write!(f, "invalid settings : {}", e),
+            Error::UnknownChat(ref chat) => write!(f, "unknown {}", chat),
+            Error::BaleError(ref e) => e.fmt(f),
             Error::ChannelRecvErr(ref e) => e.fmt(f),
             Error::ParseError(ref e) => e.fmt(f),
+            Error::DecodeError(ref e) => e.fmt(f),
+            Error::JsonError(ref e) => e.fmt(f),
             Error::ParseIntError(ref e) => e.fmt(f),
             Error::ParseUtf8Error(ref e) => e.fmt(f),
             Error::FromUtf8Error(ref e) => e.fmt(f),
@@ -32,8 +49,15 @@ impl std::error::Error for Error {
         match *self {
             Error::InternalError(ref _e) => None,
             Error::ServerError(ref _e) => None,
+            Error::InvalidFrame(ref _e) => None,
+            Error::CryptoError(ref _e) => None,
+            Error::InvalidSettings(ref _e) => None,
+            Error::UnknownChat(ref _chat) => None,
+            Error::BaleError(ref e) => Some(e),
             Error::ChannelRecvErr(ref _e) => None,
             Error::ParseError(ref e) => Some(e),
+            Error::DecodeError(ref e) => Some(e),
+            Error::JsonError(ref e) => Some(e),
             Error::ParseIntError(ref e) => Some(e),
             Error::ParseUtf8Error(ref e) => Some(e),
             Error::FromUtf8Error(ref e) => Some(e),
@@ -77,3 +101,21 @@ impl From<url::ParseError> for Error {
         Error::ParseError(err)
     }
 }
+
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Error {
+        Error::DecodeError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
+impl From<bale::Error> for Error {
+    fn from(err: bale::Error) -> Error {
+        Error::BaleError(err)
+    }
+}
@@ -0,0 +1,263 @@
+use std::convert::TryInto;
+use std::io;
+
+use crate::error::Error;
+
+const FRAME_OPEN: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_FIN: u8 = 3;
+const FRAME_RESET: u8 = 4;
+const FRAME_CONNECT: u8 = 5;
+const FRAME_DATAGRAM: u8 = 6;
+const FRAME_STATUS: u8 = 7;
+
+// kind (1 byte) + stream id (4 bytes)
+pub(crate) const FRAME_HEADER_SIZE: usize = 5;
+// length of the payloads and targets of frames
+pub(crate) const DATA_LENGTH_SIZE: usize = 2;
+
+/// Unit of data exchanged between the two ends of the tunnel.
+///
+/// Every frame belongs to a stream, which maps to exactly one TCP connection
+/// on each side of the tunnel, or to a UDP flow. Streams and flows share ids.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Frame {
+    /// Opens a stream to the server's upstream, as a shadowsocks plugin.
+    Open {
+        stream_id: u32,
+    },
+    /// Opens a stream to `target`, a `host:port` the server dials itself.
+    Connect {
+        stream_id: u32,
+        target: String,
+    },
+    Data {
+        stream_id: u32,
+        payload: Vec<u8>,
+    },
+    /// The sender won't send more data, but still reads what comes.
+    Fin {
+        stream_id: u32,
+    },
+    /// The connection failed or was aborted, drop the stream on both sides.
+    Reset {
+        stream_id: u32,
+    },
+    /// Whether the server could open the stream. Failures close the stream.
+    Status {
+        stream_id: u32,
+        status: StreamStatus,
+    },
+    /// UDP packet of a flow, to `target` when sent by the client or from
+    /// `target` when sent by the server. An empty target is the upstream.
+    Datagram {
+        flow_id: u32,
+        target: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl Frame {
+    pub(crate) fn stream_id(&self) -> u32 {
+        match *self {
+            Frame::Open { stream_id } => stream_id,
+            Frame::Connect { stream_id, .. } => stream_id,
+            Frame::Data { stream_id, .. } => stream_id,
+            Frame::Fin { stream_id } => stream_id,
+            Frame::Reset { stream_id } => stream_id,
+            Frame::Status { stream_id, .. } => stream_id,
+            Frame::Datagram { flow_id, .. } => flow_id,
+        }
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        match self {
+            Frame::Data { payload, .. } => FRAME_HEADER_SIZE + DATA_LENGTH_SIZE + payload.len(),
+            Frame::Connect { target, .. } => FRAME_HEADER_SIZE + DATA_LENGTH_SIZE + target.len(),
+            Frame::Datagram {
+                target, payload, ..
+            } => FRAME_HEADER_SIZE + 2 * DATA_LENGTH_SIZE + target.len() + payload.len(),
+            Frame::Status { .. } => FRAME_HEADER_SIZE + 1,
+            _ => FRAME_HEADER_SIZE,
+        }
+    }
+
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        let kind = match self {
+            Frame::Open { .. } => FRAME_OPEN,
+            Frame::Connect { .. } => FRAME_CONNECT,
+            Frame::Data { .. } => FRAME_DATA,
+            Frame::Fin { .. } => FRAME_FIN,
+            Frame::Reset { .. } => FRAME_RESET,
+            Frame::Status { .. } => FRAME_STATUS,
+            Frame::Datagram { .. } => FRAME_DATAGRAM,
+        };
+        buf.push(kind);
+        buf.extend_from_slice(&self.stream_id().to_be_bytes());
+
+        if let Frame::Status { status, .. } = self {
+            buf.push(*status as u8);
+        }
+
+        let fields = match self {
+            Frame::Data { payload, .. } => vec![payload.as_slice()],
+            Frame::Connect { target, .. } => vec![target.as_bytes()],
+            Frame::Datagram {
+                target, payload, ..
+            } => vec![target.as_bytes(), payload.as_slice()],
+            _ => return,
+        };
+        for field in fields {
+            assert!(field.len() <= u16::MAX as usize);
+            buf.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            buf.extend_from_slice(field);
+        }
+    }
+
+    /// Decodes the frame at the start of `buf` and advances it past the frame.
+    pub(crate) fn decode(buf: &mut &[u8]) -> Result<Frame, Error> {
+        let header = take(buf, FRAME_HEADER_SIZE)?;
+        let stream_id = u32::from_be_bytes(header[1..].try_into().unwrap());
+
+        match header[0] {
+            FRAME_OPEN => Ok(Frame::Open { stream_id }),
+            FRAME_DATA => Ok(Frame::Data {
+                stream_id,
+                payload: take_payload(buf)?.to_vec(),
+            }),
+            FRAME_CONNECT => Ok(Frame::Connect {
+                stream_id,
+                target: take_target(buf)?,
+            }),
+            FRAME_STATUS => Ok(Frame::Status {
+                stream_id,
+                status: StreamStatus::from_byte(take(buf, 1)?[0]),
+            }),
+            FRAME_DATAGRAM => Ok(Frame::Datagram {
+                flow_id: stream_id,
+                target: take_target(buf)?,
+                payload: take_payload(buf)?.to_vec(),
+            }),
+            FRAME_FIN => Ok(Frame::Fin { stream_id }),
+            FRAME_RESET => Ok(Frame::Reset { stream_id }),
+            kind => Err(Error::InvalidFrame(format!("unknown frame kind {}", kind))),
+        }
+    }
+}
+
+/// Outcome of opening a stream on the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StreamStatus {
+    Connected = 0,
+    Refused = 1,
+    Unreachable = 2,
+    TimedOut = 3,
+}
+
+impl StreamStatus {
+    fn from_byte(byte: u8) -> StreamStatus {
+        match byte {
+            0 => StreamStatus::Connected,
+            1 => StreamStatus::Refused,
+            3 => StreamStatus::TimedOut,
+            _ => StreamStatus::Unreachable,
+        }
+    }
+}
+
+impl From<&io::Error> for StreamStatus {
+    fn from(err: &io::Error) -> StreamStatus {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => StreamStatus::Refused,
+            io::ErrorKind::TimedOut => StreamStatus::TimedOut,
+            _ => StreamStatus::Unreachable,
+        }
+    }
+}
+
+/// Takes a payload prefixed with its length.
+fn take_payload<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let length = u16::from_be_bytes(take(buf, DATA_LENGTH_SIZE)?.try_into().unwrap());
+    take(buf, length as usize)
+}
+
+fn take_target(buf: &mut &[u8]) -> Result<String, Error> {
+    std::str::from_utf8(take_payload(buf)?)
+        .map(str::to_string)
+        .map_err(|_| Error::InvalidFrame("target is not UTF-8".to_string()))
+}
+
+fn take<'a>(buf: &mut &'a [u8], size: usize) -> Result<&'a [u8], Error> {
+    if buf.len() < size {
+        return Err(Error::InvalidFrame(format!(
+            "frame is truncated, expected {} more bytes but got {}",
+            size,
+            buf.len()
+        )));
+    }
+    let (head, tail) = buf.split_at(size);
+    *buf = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let frames = vec![
+            Frame::Open { stream_id: 1 },
+            Frame::Connect {
+                stream_id: 4,
+                target: "example.com:443".to_string(),
+            },
+            Frame::Data {
+                stream_id: 2,
+                payload: b"hello".to_vec(),
+            },
+            Frame::Data {
+                stream_id: 3,
+                payload: vec![],
+            },
+            Frame::Status {
+                stream_id: 4,
+                status: StreamStatus::TimedOut,
+            },
+            Frame::Fin { stream_id: 2 },
+            Frame::Datagram {
+                flow_id: 5,
+                target: "[::1]:53".to_string(),
+                payload: b"query".to_vec(),
+            },
+            Frame::Reset {
+                stream_id: u32::MAX,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for frame in &frames {
+            frame.encode(&mut buf);
+        }
+        assert_eq!(
+            buf.len(),
+            frames.iter().map(Frame::encoded_len).sum::<usize>()
+        );
+
+        let mut rest = buf.as_slice();
+        for frame in frames {
+            assert_eq!(Frame::decode(&mut rest).unwrap(), frame);
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(Frame::decode(&mut &[][..]).is_err());
+        assert!(Frame::decode(&mut &[FRAME_DATA, 0, 0][..]).is_err());
+        assert!(Frame::decode(&mut &[FRAME_DATA, 0, 0, 0, 1, 0, 3, 1][..]).is_err());
+        assert!(Frame::decode(&mut &[FRAME_CONNECT, 0, 0, 0, 1, 0, 1, 0xff][..]).is_err());
+        assert!(Frame::decode(&mut &[FRAME_DATAGRAM, 0, 0, 0, 1, 0, 0][..]).is_err());
+        assert!(Frame::decode(&mut &[42, 0, 0, 0, 1][..]).is_err());
+    }
+}
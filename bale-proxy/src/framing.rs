@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use tracing::debug;
+
+use crate::error::Error;
+use crate::frame::{Frame, DATA_LENGTH_SIZE, FRAME_HEADER_SIZE};
+use crate::reliability::SEND_WINDOW;
+
+pub(crate) const SEQUENCE_SIZE: usize = 8;
+
+// Messages ahead of the next expected one that are kept until it arrives.
+const RECEIVE_WINDOW: u64 = 2 * SEND_WINDOW as u64;
+
+// Don't bother splitting a data frame if less than this many bytes of it fit.
+const MIN_SPLIT_SIZE: usize = 16;
+
+/// Packs frames into as few messages as possible.
+///
+/// Each message starts with a sequence number followed by whole frames, and is
+/// never longer than `max_size` bytes. Data frames that don't fit are split
+/// between messages, which is fine since their payload is part of a byte stream.
+/// Other frames can't be split, so the ones longer than a message are refused.
+pub(crate) struct Packer {
+    max_size: usize,
+    next_sequence: u64,
+}
+
+impl Packer {
+    pub(crate) fn new(max_size: usize) -> Packer {
+        assert!(max_size >= SEQUENCE_SIZE + FRAME_HEADER_SIZE + DATA_LENGTH_SIZE + MIN_SPLIT_SIZE);
+        Packer {
+            max_size,
+            next_sequence: 0,
+        }
+    }
+
+    pub(crate) fn pack(&mut self, frames: Vec<Frame>) -> Result<Vec<Vec<u8>>, Error> {
+        self.pack_within(frames, self.max_size)
+    }
+
+    /// Same as [`Packer::pack`], but with messages of up to `max_size` bytes.
+    /// The sequence numbers are shared, so both kinds of messages can be mixed.
+    pub(crate) fn pack_within(
+        &mut self,
+        frames: Vec<Frame>,
+        max_size: usize,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        assert!(max_size >= self.max_size);
+        check_fit(&frames, max_size)?;
+
+        let mut messages = Vec::new();
+        let mut current = Vec::with_capacity(max_size);
+
+        for mut frame in frames {
+            loop {
+                let room = max_size - SEQUENCE_SIZE - current.len();
+                if frame.encoded_len() <= room {
+                    frame.encode(&mut current);
+                    break;
+                }
+
+                if let Frame::Data { stream_id, payload } = &mut frame {
+                    let overhead = FRAME_HEADER_SIZE + DATA_LENGTH_SIZE;
+                    if room >= overhead + MIN_SPLIT_SIZE {
+                        let rest = payload.split_off(room - overhead);
+                        Frame::Data {
+                            stream_id: *stream_id,
+                            payload: std::mem::replace(payload, rest),
+                        }
+                        .encode(&mut current);
+                    }
+                }
+
+                messages.push(self.finish(&mut current));
+            }
+        }
+
+        if !current.is_empty() {
+            messages.push(self.finish(&mut current));
+        }
+        Ok(messages)
+    }
+
+    /// Fails if a frame is too long to ever be packed by [`Packer::pack`].
+    pub(crate) fn check(&self, frames: &[Frame]) -> Result<(), Error> {
+        check_fit(frames, self.max_size)
+    }
+
+    fn finish(&mut self, frames: &mut Vec<u8>) -> Vec<u8> {
+        let mut message = Vec::with_capacity(SEQUENCE_SIZE + frames.len());
+        message.extend_from_slice(&self.next_sequence.to_be_bytes());
+        message.append(frames);
+        self.next_sequence += 1;
+        message
+    }
+}
+
+fn check_fit(frames: &[Frame], max_size: usize) -> Result<(), Error> {
+    let too_long = frames.iter().find(|frame| {
+        !matches!(frame, Frame::Data { .. }) && frame.encoded_len() > max_size - SEQUENCE_SIZE
+    });
+    match too_long {
+        Some(frame) => Err(Error::InvalidFrame(format!(
+            "frame of stream #{} is {} bytes, more than a message holds",
+            frame.stream_id(),
+            frame.encoded_len()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Puts messages made by a [`Packer`] back in order and unpacks their frames.
+///
+/// Messages too far ahead of the next expected one are dropped, the peer
+/// sends them again once they are acknowledged.
+pub(crate) struct Reassembler {
+    next_sequence: u64,
+    pending: BTreeMap<u64, Vec<Frame>>,
+}
+
+impl Reassembler {
+    pub(crate) fn new() -> Reassembler {
+        Reassembler {
+            next_sequence: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the frames that are ready to be used, in the order they were packed.
+    pub(crate) fn push(&mut self, message: &[u8]) -> Result<Vec<Frame>, Error> {
+        if message.len() < SEQUENCE_SIZE {
+            return Err(Error::InvalidFrame("message is too short".to_string()));
+        }
+        let sequence = u64::from_be_bytes(message[..SEQUENCE_SIZE].try_into().unwrap());
+
+        let mut rest = &message[SEQUENCE_SIZE..];
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            frames.push(Frame::decode(&mut rest)?);
+        }
+
+        if sequence >= self.next_sequence + RECEIVE_WINDOW {
+            debug!("dropping message #{} beyond the receive window", sequence);
+            return Ok(Vec::new());
+        }
+        if sequence >= self.next_sequence {
+            self.pending.insert(sequence, frames);
+        }
+
+        let mut ready = Vec::new();
+        while let Some(frames) = self.pending.remove(&self.next_sequence) {
+            ready.extend(frames);
+            self.next_sequence += 1;
+        }
+        Ok(ready)
+    }
+
+    /// Sequence number of the first message still missing, which acknowledges
+    /// every message before it.
+    pub(crate) fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_frames_within_size_limit() {
+        let frames = vec![
+            Frame::Open { stream_id: 1 },
+            Frame::Data {
+                stream_id: 1,
+                payload: (0..=255).cycle().take(1000).collect(),
+            },
+            Frame::Open { stream_id: 2 },
+            Frame::Data {
+                stream_id: 2,
+                payload: vec![7; 10],
+            },
+            Frame::Fin { stream_id: 1 },
+        ];
+
+        let mut packer = Packer::new(128);
+        let messages = packer.pack(frames.clone()).unwrap();
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|message| message.len() <= 128));
+
+        let mut reassembler = Reassembler::new();
+        let mut unpacked = Vec::new();
+        for message in messages.iter().rev() {
+            unpacked.extend(reassembler.push(message).unwrap());
+        }
+
+        let data = |stream: u32, frames: &[Frame]| -> Vec<u8> {
+            frames
+                .iter()
+                .filter_map(|frame| match frame {
+                    Frame::Data { stream_id, payload } if *stream_id == stream => {
+                        Some(payload.clone())
+                    }
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        };
+        assert_eq!(data(1, &unpacked), data(1, &frames));
+        assert_eq!(data(2, &unpacked), data(2, &frames));
+        assert_eq!(unpacked.first(), frames.first());
+        assert_eq!(unpacked.last(), frames.last());
+    }
+
+    #[test]
+    fn ignores_duplicated_messages() {
+        let mut packer = Packer::new(64);
+        let messages = packer
+            .pack(vec![
+                Frame::Open { stream_id: 1 },
+                Frame::Fin { stream_id: 1 },
+            ])
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&messages[0]).unwrap().len(), 2);
+        assert!(reassembler.push(&messages[0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_messages_beyond_window() {
+        let mut packer = Packer::new(64);
+        let messages: Vec<_> = (0..=RECEIVE_WINDOW)
+            .flat_map(|_| packer.pack(vec![Frame::Open { stream_id: 1 }]).unwrap())
+            .collect();
+
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler
+            .push(messages.last().unwrap())
+            .unwrap()
+            .is_empty());
+        assert!(reassembler.push(&messages[1]).unwrap().is_empty());
+        assert_eq!(reassembler.push(&messages[0]).unwrap().len(), 2);
+        assert_eq!(reassembler.next_sequence(), 2);
+    }
+
+    #[test]
+    fn mixes_message_sizes() {
+        let data = |payload: Vec<u8>| Frame::Data {
+            stream_id: 1,
+            payload,
+        };
+
+        let mut packer = Packer::new(64);
+        let mut messages = packer.pack(vec![data(vec![1; 100])]).unwrap();
+        let large = packer.pack_within(vec![data(vec![2; 1000])], 4096).unwrap();
+        assert_eq!(large.len(), 1);
+        messages.extend(large);
+        messages.extend(packer.pack(vec![data(vec![3; 10])]).unwrap());
+
+        let mut reassembler = Reassembler::new();
+        let mut payload = Vec::new();
+        for message in messages.iter().rev() {
+            for frame in reassembler.push(message).unwrap() {
+                if let Frame::Data { payload: data, .. } = frame {
+                    payload.extend(data);
+                }
+            }
+        }
+        assert_eq!(payload.len(), 1110);
+        assert!(payload[..100].iter().all(|&b| b == 1));
+        assert!(payload[100..1100].iter().all(|&b| b == 2));
+        assert!(payload[1100..].iter().all(|&b| b == 3));
+    }
+
+    #[test]
+    fn refuses_frames_longer_than_a_message() {
+        let mut packer = Packer::new(64);
+        let connect = Frame::Connect {
+            stream_id: 2,
+            target: format!("{}.example:443", "a".repeat(100)),
+        };
+        assert!(packer
+            .pack(vec![Frame::Open { stream_id: 1 }, connect.clone()])
+            .is_err());
+        assert_eq!(packer.pack_within(vec![connect], 256).unwrap().len(), 1);
+
+        // Nothing was packed for the refused batch.
+        let messages = packer.pack(vec![Frame::Open { stream_id: 3 }]).unwrap();
+        assert_eq!(messages[0][..SEQUENCE_SIZE], 1u64.to_be_bytes());
+    }
+}
@@ -0,0 +1,223 @@
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+use crate::error::Error;
+use crate::frame::StreamStatus;
+
+// Requests with a bigger head are refused.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+// Longest host accepted, the most a domain name can be and what SOCKS5 allows.
+const MAX_HOST_LENGTH: usize = 255;
+// Headers meant for the proxy or for this hop only, not sent to origin servers.
+const HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+const CONNECTION_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+const BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n";
+const GATEWAY_TIMEOUT: &[u8] = b"HTTP/1.1 504 Gateway Timeout\r\nConnection: close\r\n\r\n";
+
+/// What an HTTP proxy client asked for.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Request {
+    /// `CONNECT` to `host:port`, with the bytes the client sent after its
+    /// request.
+    Connect(String, Vec<u8>),
+    /// Plain request to `host:port`, with the bytes to send there first.
+    Forward(String, Vec<u8>),
+}
+
+/// Reads the first request of a new HTTP proxy client.
+///
+/// Plain requests with an absolute URI are sent on with the URI turned into a
+/// path, without the headers meant for the proxy, and with `Connection: close`
+/// so the origin server answers a single request. Clients open a new
+/// connection for the next one, which may go to another host. Malformed
+/// requests are answered here, the others with [`reply_status`] once the
+/// server on the other side of the tunnel tried them.
+pub(crate) async fn accept<S>(stream: &mut S) -> Result<Request, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, rest) = read_head(stream).await?;
+    match parse_request(&head) {
+        Ok(Request::Connect(target, _)) => Ok(Request::Connect(target, rest)),
+        Ok(Request::Forward(target, mut initial)) => {
+            initial.extend_from_slice(&rest);
+            Ok(Request::Forward(target, initial))
+        }
+        Err(err) => {
+            stream.write_all(BAD_REQUEST).await?;
+            Err(err)
+        }
+    }
+}
+
+/// Tells the client whether the server could connect. Successful plain
+/// requests get their answer from the origin server instead.
+pub(crate) async fn reply_status<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    connect: bool,
+    status: StreamStatus,
+) -> Result<(), Error> {
+    let reply = match status {
+        StreamStatus::Connected if connect => CONNECTION_ESTABLISHED,
+        StreamStatus::Connected => return Ok(()),
+        StreamStatus::TimedOut => GATEWAY_TIMEOUT,
+        StreamStatus::Refused | StreamStatus::Unreachable => BAD_GATEWAY,
+    };
+    stream.write_all(reply).await?;
+    Ok(())
+}
+
+fn parse_request(head: &str) -> Result<Request, Error> {
+    let bad_request = || Error::InvalidFrame("malformed HTTP proxy request".to_string());
+
+    let (request_line, headers) = head.split_once("\r\n").ok_or_else(bad_request)?;
+    let mut parts = request_line.split(' ');
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version), None) => (method, uri, version),
+        _ => return Err(bad_request()),
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = uri.rsplit_once(':').ok_or_else(bad_request)?;
+        if host.is_empty() || host.len() > MAX_HOST_LENGTH || port.parse::<u16>().is_err() {
+            return Err(bad_request());
+        }
+        return Ok(Request::Connect(uri.to_string(), Vec::new()));
+    }
+
+    let url = Url::parse(uri).map_err(|_| bad_request())?;
+    if url.scheme() != "http" {
+        return Err(Error::InvalidFrame(format!(
+            "unsupported scheme {} in HTTP proxy request",
+            url.scheme()
+        )));
+    }
+    let host = url.host_str().ok_or_else(bad_request)?;
+    if host.len() > MAX_HOST_LENGTH {
+        return Err(bad_request());
+    }
+    let port = url.port_or_known_default().ok_or_else(bad_request)?;
+
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let mut forwarded = format!("{} {} {}\r\n", method, path, version);
+    for header in headers.split("\r\n").filter(|header| !header.is_empty()) {
+        let name = header.split(':').next().unwrap_or_default().trim();
+        if !HOP_HEADERS.iter().any(|hop| hop.eq_ignore_ascii_case(name)) {
+            forwarded.push_str(header);
+            forwarded.push_str("\r\n");
+        }
+    }
+    forwarded.push_str("Connection: close\r\n\r\n");
+    Ok(Request::Forward(
+        format!("{}:{}", host, port),
+        forwarded.into_bytes(),
+    ))
+}
+
+/// Reads until the end of the request head, and returns it along with the
+/// bytes read after it.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(String, Vec<u8>), Error> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(Error::InvalidFrame(
+                "HTTP proxy client closed the connection".to_string(),
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buffer.split_off(end + 4);
+            return Ok((String::from_utf8(buffer)?, rest));
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(Error::InvalidFrame(
+                "HTTP proxy request head is too big".to_string(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward(head: &str) -> (String, String) {
+        match parse_request(head).unwrap() {
+            Request::Forward(target, head) => (target, String::from_utf8(head).unwrap()),
+            Request::Connect(..) => panic!("expected a plain request"),
+        }
+    }
+
+    #[test]
+    fn parses_proxy_requests() {
+        match parse_request("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n") {
+            Ok(Request::Connect(target, _)) => assert_eq!(target, "example.com:443"),
+            _ => panic!("expected a CONNECT request"),
+        }
+
+        assert_eq!(
+            forward("GET http://example.com/a/b?c=d HTTP/1.1\r\nHost: example.com\r\n\r\n"),
+            (
+                "example.com:80".to_string(),
+                "GET /a/b?c=d HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            forward("POST http://[::1]:8080 HTTP/1.0\r\n\r\n").0,
+            "[::1]:8080"
+        );
+
+        assert_eq!(
+            forward(
+                "GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+                 Proxy-Connection: keep-alive\r\nproxy-authorization: Basic YTpi\r\n\
+                 Connection: keep-alive\r\nAccept: */*\r\n\r\n"
+            )
+            .1,
+            "GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+
+        assert!(parse_request("GET /index.html HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request("GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request("CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn refuses_long_hosts() {
+        let host = format!("{}.example", "a".repeat(MAX_HOST_LENGTH));
+        let connect = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host);
+        assert!(parse_request(&connect).is_err());
+        let get = format!("GET http://{}/ HTTP/1.1\r\n\r\n", host);
+        assert!(parse_request(&get).is_err());
+
+        let host = "a".repeat(MAX_HOST_LENGTH);
+        let connect = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", host);
+        assert!(parse_request(&connect).is_ok());
+    }
+
+    #[async_std::test]
+    async fn keeps_bytes_sent_after_connect() {
+        let mut stream = futures::io::Cursor::new(
+            b"CONNECT example.com:443 HTTP/1.1\r\n\r\n\x16\x03\x01".to_vec(),
+        );
+        assert_eq!(
+            accept(&mut stream).await.unwrap(),
+            Request::Connect("example.com:443".to_string(), vec![0x16, 3, 1])
+        );
+    }
+}
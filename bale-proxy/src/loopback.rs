@@ -0,0 +1,307 @@
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::error::Error;
+use crate::transport::{
+    Chat, Document, Message, MessageId, MessengerTransport, OutboxStats, Received,
+};
+
+/// How badly the loopback network treats messages. The default delivers
+/// every message once, right away and in order.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Conditions {
+    /// Delay of every message.
+    pub(crate) latency: Duration,
+    /// Extra random delay of up to this much, which reorders messages.
+    pub(crate) reordering: Duration,
+    /// Probability for a message to be delivered twice.
+    pub(crate) duplication: f64,
+    /// Probability for a message to be lost.
+    pub(crate) loss: f64,
+}
+
+/// Messenger inside the process, connecting the transports that joined it.
+///
+/// Conditions are drawn from a seeded generator, so a failing test can be
+/// run again with the same losses and delays.
+pub(crate) struct Network {
+    conditions: Conditions,
+    rng: Mutex<StdRng>,
+    inboxes: Mutex<HashMap<u32, Sender<Received>>>,
+    groups: Mutex<HashMap<u32, Vec<u32>>>,
+    files: Mutex<HashMap<i64, Vec<u8>>>,
+    deleted: Mutex<Vec<MessageId>>,
+    next_id: AtomicU64,
+}
+
+impl Network {
+    pub(crate) fn new(conditions: Conditions, seed: u64) -> Arc<Network> {
+        Arc::new(Network {
+            conditions,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+            inboxes: Mutex::new(HashMap::new()),
+            groups: Mutex::new(HashMap::new()),
+            files: Mutex::new(HashMap::new()),
+            deleted: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Account of `user_id` on this network.
+    pub(crate) fn join(self: &Arc<Self>, user_id: u32) -> LoopbackTransport {
+        let (inbox_tx, inbox_rx) = unbounded();
+        self.inboxes.lock().unwrap().insert(user_id, inbox_tx);
+        let (outbox_tx, outbox_rx) = unbounded();
+        LoopbackTransport {
+            user_id,
+            network: self.clone(),
+            inbox_rx,
+            outbox_tx,
+            outbox_rx,
+            stats: watch::channel(OutboxStats::default()).0,
+        }
+    }
+
+    /// Creates a group of the users and returns its chat.
+    pub(crate) fn create_group(&self, group_id: u32, members: &[u32]) -> Chat {
+        self.groups
+            .lock()
+            .unwrap()
+            .insert(group_id, members.to_vec());
+        Chat::Group(group_id)
+    }
+
+    /// Messages the accounts asked to delete, oldest first.
+    pub(crate) fn deleted(&self) -> Vec<MessageId> {
+        self.deleted.lock().unwrap().clone()
+    }
+
+    /// Delivers the message to the other members of the chat, unless it is
+    /// lost. Returns false if the chat is unknown.
+    fn deliver(&self, sender_id: u32, chat: Chat, message: Message) -> bool {
+        // Receivers see private chats named after the sender.
+        let (members, received_in) = match chat {
+            Chat::User(user_id) => (vec![user_id], Chat::User(sender_id)),
+            Chat::Group(group_id) => match self.groups.lock().unwrap().get(&group_id) {
+                Some(members) if members.contains(&sender_id) => (members.clone(), chat),
+                _ => return false,
+            },
+        };
+        let inboxes: Vec<Sender<Received>> = {
+            let inboxes = self.inboxes.lock().unwrap();
+            members
+                .iter()
+                .filter(|&&user_id| user_id != sender_id)
+                .filter_map(|user_id| inboxes.get(user_id).cloned())
+                .collect()
+        };
+        if inboxes.is_empty() && chat == Chat::User(members[0]) {
+            return false;
+        }
+
+        let rid = self.next_id.fetch_add(1, Ordering::Relaxed);
+        for inbox in inboxes {
+            for delay in self.delays() {
+                let received = Received {
+                    chat: received_in,
+                    sender_id,
+                    id: MessageId { rid, date: rid },
+                    message: message.clone(),
+                };
+                let inbox = inbox.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = inbox.send(received).await;
+                });
+            }
+        }
+        true
+    }
+
+    /// Delay of every copy of a message, none if it is lost.
+    fn delays(&self) -> Vec<Duration> {
+        let mut rng = self.rng.lock().unwrap();
+        let copies = if rng.gen_bool(self.conditions.loss) {
+            0
+        } else if rng.gen_bool(self.conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| self.conditions.latency + self.conditions.reordering.mul_f64(rng.gen()))
+            .collect()
+    }
+}
+
+/// Transport of an account on a loopback [`Network`].
+pub(crate) struct LoopbackTransport {
+    user_id: u32,
+    network: Arc<Network>,
+    inbox_rx: Receiver<Received>,
+    outbox_tx: Sender<(Chat, Message)>,
+    outbox_rx: Receiver<(Chat, Message)>,
+    stats: watch::Sender<OutboxStats>,
+}
+
+impl LoopbackTransport {
+    fn queue(&self, chat: Chat, message: Message) {
+        self.outbox_tx
+            .try_send((chat, message))
+            .expect("outbox is unbounded");
+        self.stats
+            .send_modify(|stats| stats.queued = self.outbox_rx.len());
+    }
+}
+
+#[async_trait]
+impl MessengerTransport for LoopbackTransport {
+    async fn subscribe_to_updates(&self, tx: Sender<Received>) -> Result<(), Error> {
+        while let Ok(received) = self.inbox_rx.recv().await {
+            if tx.send(received).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_outbox(&self, failed_tx: Sender<(Chat, Error)>) -> Result<(), Error> {
+        while let Ok((chat, message)) = self.outbox_rx.recv().await {
+            self.stats.send_modify(|stats| {
+                stats.queued = self.outbox_rx.len();
+                stats.sent += 1;
+            });
+            if !self.network.deliver(self.user_id, chat, message)
+                && failed_tx
+                    .send((chat, Error::UnknownChat(chat)))
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn queue_message(&self, chat: Chat, text: String) {
+        self.queue(chat, Message::Text(text));
+    }
+
+    fn queue_document(&self, chat: Chat, name: String, content: Vec<u8>) {
+        let file_id = self.network.next_id.fetch_add(1, Ordering::Relaxed) as i64;
+        let document = Document {
+            file_id,
+            access_hash: 0,
+            size: content.len() as i32,
+            name,
+            mime_type: "application/octet-stream".to_string(),
+        };
+        self.network.files.lock().unwrap().insert(file_id, content);
+        self.queue(chat, Message::Document(document));
+    }
+
+    /// There is no chat history to delete from, the network only remembers
+    /// what was deleted.
+    fn queue_delete(&self, _chat: Chat, ids: Vec<MessageId>) {
+        self.network.deleted.lock().unwrap().extend(ids);
+    }
+
+    fn outbox_stats(&self) -> watch::Receiver<OutboxStats> {
+        self.stats.subscribe()
+    }
+
+    async fn download_file(&self, document: &Document) -> Result<Vec<u8>, Error> {
+        let files = self.network.files.lock().unwrap();
+        files
+            .get(&document.file_id)
+            .cloned()
+            .ok_or_else(|| Error::InternalError("unknown file".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn send(conditions: Conditions, count: usize) -> Vec<String> {
+        let network = Network::new(conditions, 0);
+        let alice = network.join(1);
+        let bob = network.join(2);
+        for i in 0..count {
+            alice.queue_message(Chat::User(2), i.to_string());
+        }
+        let (failed_tx, _failed_rx) = unbounded();
+        tokio::spawn(async move { alice.run_outbox(failed_tx).await });
+
+        let mut texts = Vec::new();
+        while let Ok(Ok(received)) =
+            tokio::time::timeout(Duration::from_millis(100), bob.inbox_rx.recv()).await
+        {
+            assert_eq!((received.chat, received.sender_id), (Chat::User(1), 1));
+            match received.message {
+                Message::Text(text) => texts.push(text),
+                Message::Document(_) => panic!("expected a text message"),
+            }
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn applies_conditions() {
+        let texts = send(Conditions::default(), 3).await;
+        assert_eq!(texts, vec!["0", "1", "2"]);
+
+        let duplicated = Conditions {
+            duplication: 1.0,
+            ..Conditions::default()
+        };
+        assert_eq!(send(duplicated, 3).await.len(), 6);
+
+        let lost = Conditions {
+            loss: 1.0,
+            ..Conditions::default()
+        };
+        assert!(send(lost, 3).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn delivers_to_other_group_members() {
+        let network = Network::new(Conditions::default(), 0);
+        let alice = network.join(1);
+        let bob = network.join(2);
+        let carol = network.join(3);
+        let group = network.create_group(10, &[1, 2, 3]);
+        alice.queue_message(group, "hello".to_string());
+
+        let (failed_tx, _failed_rx) = unbounded();
+        tokio::spawn(async move { alice.run_outbox(failed_tx).await });
+        for member in [bob, carol].iter() {
+            let received = member.inbox_rx.recv().await.unwrap();
+            assert_eq!((received.chat, received.sender_id), (group, 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_chats() {
+        let network = Network::new(Conditions::default(), 0);
+        let alice = network.join(1);
+        network.create_group(10, &[2, 3]);
+        alice.queue_message(Chat::User(3), "hello".to_string());
+        alice.queue_message(Chat::Group(10), "hello".to_string());
+
+        let (failed_tx, failed_rx) = unbounded();
+        tokio::spawn(async move { alice.run_outbox(failed_tx).await });
+        for chat in [Chat::User(3), Chat::Group(10)].iter() {
+            let (failed, err) = failed_rx.recv().await.unwrap();
+            assert_eq!(failed, *chat);
+            assert!(matches!(err, Error::UnknownChat(unknown) if unknown == *chat));
+        }
+    }
+}
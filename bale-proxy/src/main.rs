@@ -1,134 +1,292 @@
-use async_std::net::SocketAddrV4;
-use async_std::sync::{Arc, Mutex};
+use async_std::sync::Arc;
 use std::env;
 use std::io::{stdin, stdout, Write};
+use std::path::PathBuf;
+use std::process::Command;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use tokio::select;
-use tracing::{debug, error, info, subscriber};
+use tracing::{debug, error, info, subscriber, warn};
 use tracing_subscriber::FmtSubscriber;
 
+mod codec;
+mod config;
+mod crypto;
 mod error;
+mod frame;
+mod framing;
+mod http_proxy;
+#[cfg(test)]
+mod loopback;
+mod reliability;
+mod session;
 mod simple_server;
 mod socket;
+mod socks5;
+mod transport;
+mod tunnel;
+mod udp;
 mod utils;
-use crate::utils::dump_hex;
-use bale::{BaleClient, LoginStatus};
+use crate::codec::PayloadCodec;
+use crate::config::Config;
+use crate::error::Error;
+use crate::frame::Frame;
+use bale::{BaleClient, CredentialStore, LoginStatus, RateLimit};
 use simple_server::get_from_web;
-use socket::Socket;
+use socket::{Listener, Socket};
+use transport::{Chat, MessengerTransport, Received};
+use tunnel::Tunnel;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 struct BaleProxy {
     client: BaleClient,
+    user_id: u32,
     run_params: RunParams,
+    static_key: Option<StaticSecret>,
+    server_key: Option<PublicKey>,
 }
 
 impl BaleProxy {
     async fn connect(run_params: RunParams) -> BaleProxy {
         let run_params_clone = run_params.clone();
         let phone_number = run_params.phone_number.expect("bad phone number");
-        let mut bale = BaleClient::new(phone_number);
+        let mut bale = BaleClient::builder(phone_number);
+        if let Some(endpoint) = run_params.endpoint.as_deref() {
+            bale = bale.endpoint(endpoint);
+        }
+        if let Some(api_key) = run_params.api_key.as_deref() {
+            bale = bale.api_key(api_key);
+        }
+        if let Some(client_version) = run_params.client_version {
+            bale = bale.client_version(client_version);
+        }
+        if let Some(user_agent) = run_params.user_agent.as_deref() {
+            bale = bale.user_agent(user_agent);
+        }
+        if let Some(path) = run_params.root_certificate.as_deref() {
+            bale = bale
+                .root_certificate(std::fs::read(path).expect("could not read root certificate"));
+        }
+        let mut bale = bale
+            .accept_invalid_certs(run_params.accept_invalid_certs)
+            .build()
+            .expect("bad Bale client settings");
+        let key_dir = run_params.credentials_dir.clone();
+        if let Some(dir) = key_dir.as_ref() {
+            bale = bale.with_credential_store(CredentialStore::new(dir));
+        }
+        bale = bale.with_rate_limit(RateLimit {
+            per_second: run_params.rate_limit,
+            ..RateLimit::default()
+        });
 
-        let login_status = if let Some(jwt) = run_params.jwt {
-            let mut res = bale.login_with(jwt.to_string()).await;
-            if matches!(res, LoginStatus::Expired) {
-                res = bale.login().await;
+        let login_status = if let Some(jwt) = run_params.jwt.clone().or_else(|| bale.saved_jwt()) {
+            match bale.login_with(jwt.to_string()).await {
+                Ok(LoginStatus::Expired) => bale.login().await,
+                res => res,
             }
-            res
         } else {
             bale.login().await
         };
 
-        let user_id = match login_status {
-            LoginStatus::LoggedIn(_jwt, user_id) => {
-                // Successful login
-                user_id
-            }
-            LoginStatus::WaitingForValidationCode(_) => {
-                let login_code = get_input(
-                    format!("Please enter login code sent by sms to {}:", phone_number),
-                    !run_params.running_from_shadowsocks,
-                )
-                .await;
-                if let Some(user_data) = bale.validate_code(&login_code).await {
-                    // Successful login
-                    user_data.user_id
-                } else {
-                    panic!("wrong validation code")
-                }
-            }
-            LoginStatus::NotRegistered => panic!("phone number is not registered"),
-            LoginStatus::Error(err) => panic!("{}", err),
-            _ => panic!("unknown error happened"),
+        let user_id = match complete_login(&bale, &run_params, login_status).await {
+            Ok(user_id) => user_id,
+            Err(err) => panic!("could not log in : {}", err),
         };
 
-        if let OperationMode::Client(_server_user_id) = run_params.mode {
-            // TODO: Handshake
-            // bale.send_message(server_user_id, "Handshaking".to_string())
-            //     .await;
+        let (static_key, server_key) = if let OperationMode::Client(_) = run_params.mode {
+            let server_key = run_params
+                .server_key
+                .as_deref()
+                .map(crypto::decode_public_key)
+                .expect("server_key is required in client mode")
+                .expect("bad server key");
+            (None, Some(server_key))
         } else {
+            let static_key = match run_params.private_key.as_deref() {
+                Some(private_key) => {
+                    crypto::decode_static_key(private_key).expect("bad private key")
+                }
+                None => {
+                    // check_run_params makes sure there is somewhere to keep it.
+                    let dir = key_dir.expect("no directory to keep the private key in");
+                    let path = dir.join(format!("{}.key", phone_number));
+                    let (static_key, created) = crypto::load_or_create_static_key(&path)
+                        .expect("could not load private key");
+                    if created {
+                        info!("Generated a new key, saved to {}", path.display());
+                    }
+                    static_key
+                }
+            };
             info!("Set {} as server id in clients", user_id);
-        }
+            info!(
+                "Set {} as server_key in clients",
+                base64::encode(PublicKey::from(&static_key).as_bytes())
+            );
+            (Some(static_key), None)
+        };
 
         BaleProxy {
             client: bale,
+            user_id,
             run_params: run_params_clone,
+            static_key,
+            server_key,
         }
     }
 
     async fn run(self) {
-        let run_params = self.run_params.clone();
+        let client = Arc::new(self.client);
+        let login_client = client.clone();
+        let login_params = self.run_params.clone();
+        tokio::spawn(async move {
+            watch_login(login_client, login_params).await;
+        });
 
-        let recipient = Arc::new(Mutex::new(
-            if let OperationMode::Client(id) = run_params.mode {
-                Some(id)
-            } else {
-                None
-            },
-        ));
+        Self::run_tunnel(
+            client,
+            self.user_id,
+            self.run_params,
+            self.static_key,
+            self.server_key,
+        )
+        .await;
+    }
 
-        let (client_tx, client_rx) = async_std::channel::unbounded::<(u32, String)>();
+    /// Carries the traffic of the local sockets over `transport`, logged in
+    /// as `user_id`, until the messenger or the sockets stop.
+    async fn run_tunnel(
+        transport: Arc<dyn MessengerTransport>,
+        user_id: u32,
+        run_params: RunParams,
+        static_key: Option<StaticSecret>,
+        server_key: Option<PublicKey>,
+    ) {
+        let (client_tx, client_rx) = async_std::channel::unbounded::<Received>();
         let (inbound_socket_tx, inbound_socket_rx) =
-            async_std::channel::unbounded::<(u32, Vec<u8>)>();
+            async_std::channel::bounded::<(u32, Frame)>(INBOUND_QUEUE_SIZE);
+        let (failed_tx, failed_rx) = async_std::channel::unbounded::<(Chat, Error)>();
         let (outbound_socket_tx, outbound_socket_rx) =
-            async_std::channel::unbounded::<(u32, Vec<u8>)>();
+            async_std::channel::unbounded::<(u32, Frame)>();
 
-        let client = Arc::new(self.client);
+        let codec = codec::codec_by_name(&run_params.codec).expect("unknown codec");
+        let server = match (&run_params.mode, server_key) {
+            (OperationMode::Client(id), Some(server_key)) => {
+                let chat = run_params.peer.unwrap_or(Chat::User(*id));
+                Some((*id, chat, server_key))
+            }
+            _ => None,
+        };
+        let allowed_users = match run_params.mode {
+            OperationMode::Server if run_params.allowed_users.is_empty() => {
+                warn!("allowed_users is not set, any Bale user can use the tunnel");
+                None
+            }
+            OperationMode::Server => Some(run_params.allowed_users.iter().copied().collect()),
+            OperationMode::Client(_) => None,
+        };
+        let document_threshold = run_params.document_threshold;
+        let auto_delete = run_params.auto_delete;
 
-        let client1 = client.clone();
+        let updates_transport = transport.clone();
         let client_handle = tokio::spawn(async move {
-            client1.subscribe_to_updates(client_tx).await;
+            if let Err(err) = updates_transport.subscribe_to_updates(client_tx).await {
+                error!("stopped receiving updates : {}", err);
+            }
         });
 
+        let outbox_transport = transport.clone();
+        tokio::spawn(async move {
+            if let Err(err) = outbox_transport.run_outbox(failed_tx).await {
+                error!("stopped sending messages : {}", err);
+            }
+        });
+
+        // Shadowsocks hands its local address to the plugin: the server
+        // connects to it and the client listens on it.
+        let local_addrs = match (&run_params.local_host, &run_params.local_port) {
+            (Some(host), Some(port)) => utils::resolve(host, port.parse().expect("bad local port"))
+                .expect("could not resolve local host"),
+            _ => Vec::new(),
+        };
+        let mut listeners: Vec<Listener> =
+            local_addrs.iter().copied().map(Listener::Forward).collect();
+        if run_params.udp {
+            listeners.extend(local_addrs.iter().copied().map(Listener::Udp));
+        }
+        if let Some(socks5) = &run_params.socks5 {
+            let addrs = utils::resolve_addrs(socks5).expect("bad socks5 address");
+            listeners.extend(addrs.into_iter().map(Listener::Socks5));
+        }
+        if let Some(http_proxy) = &run_params.http_proxy {
+            let addrs = utils::resolve_addrs(http_proxy).expect("bad http_proxy address");
+            listeners.extend(addrs.into_iter().map(Listener::Http));
+        }
+        if let OperationMode::Client(_) = run_params.mode {
+            if listeners.is_empty() {
+                panic!("nothing to listen on, run from shadowsocks or set socks5= or http_proxy=");
+            }
+        }
+
         let socket_handle = tokio::spawn(async move {
             Socket::new(inbound_socket_tx, outbound_socket_rx)
                 .connect(
                     run_params.mode,
-                    SocketAddrV4::new(
-                        run_params.local_host.unwrap().parse().unwrap(),
-                        run_params.local_port.unwrap().parse().unwrap(),
-                    ),
-                    SocketAddrV4::new(
-                        run_params.remote_host.unwrap().parse().unwrap(),
-                        run_params.remote_port.unwrap().parse().unwrap(),
-                    ),
+                    local_addrs,
+                    run_params.open_targets,
+                    listeners,
                 )
                 .await
                 .unwrap();
         });
 
-        let client2 = client.clone();
+        let mut outbox_stats = transport.outbox_stats();
+        let mut tunnel = Tunnel::new(
+            transport,
+            user_id,
+            outbound_socket_tx,
+            static_key,
+            allowed_users,
+            document_threshold,
+            auto_delete,
+        );
 
         let handler_handle = tokio::spawn(async move {
+            if let Some((id, chat, server_key)) = server {
+                tunnel.connect(id, chat, server_key, codec);
+            }
+
+            let mut retransmit = tokio::time::interval(RETRANSMIT_INTERVAL);
             loop {
+                // Stop reading from sockets while the messenger can't keep up,
+                // so TCP slows the connections down instead of the account
+                // getting banned for flooding.
+                let can_send = outbox_stats.borrow_and_update().queued < MAX_QUEUED_MESSAGES;
+
                 select! {
-                    res = inbound_socket_rx.recv() => {
+                    _ = retransmit.tick() => tunnel.tick(),
+                    _ = outbox_stats.changed() => {
+                        debug!("outbox : {}", *outbox_stats.borrow());
+                    },
+                    res = failed_rx.recv() => {
+                        match res {
+                            Ok((chat, err)) => tunnel.send_failed(chat, err),
+                            Err(err) => {
+                                error!("outbox stopped : {}", err);
+                                break;
+                            },
+                        }
+                    },
+                    res = inbound_socket_rx.recv(), if can_send => {
                         match res {
-                            Ok((_, msg)) => {
-                                info!("received msg from socket : {}", dump_hex(msg.as_ref()));
-                                if let Some(id) = *recipient.lock_arc().await {
-                                    client2.send_message(id, base64::encode(msg)).await;
-                                } else {
-                                    error!("sending message to client but recipient in unknown");
+                            Ok(frame) => {
+                                // Send whatever else is already waiting along with it.
+                                let mut frames = vec![frame];
+                                while let Ok(frame) = inbound_socket_rx.try_recv() {
+                                    frames.push(frame);
                                 }
+                                tunnel.send_frames(frames)
                             },
                             Err(err) => {
                                 error!("error receiving message from socket : {}", err);
@@ -138,21 +296,9 @@ impl BaleProxy {
                     },
                     res = client_rx.recv() => {
                         match res {
-                            Ok((id, msg)) => {
-                                info!("received msg from client : {}", dump_hex(msg.as_ref()));
-                                match base64::decode(msg) {
-                                    Ok(msg) => {
-                                        {
-                                            let mut recipient_id = recipient.lock_arc().await;
-                                            if recipient_id.is_none() {
-                                                *recipient_id = Some(id);
-                                            }
-                                        }
-                                        if let Err(err) = outbound_socket_tx.send((id, msg)).await {
-                                            error!("could not send message message to socket : {:?}", err);
-                                        }
-                                    },
-                                    Err(err) => error!("could not decode received message from client : {:?}", err),
+                            Ok(received) => {
+                                if let Err(err) = tunnel.receive(received).await {
+                                    error!("could not handle received message from client : {}", err);
                                 }
                             },
                             Err(err) => {
@@ -175,7 +321,10 @@ impl BaleProxy {
 
 #[tokio::main]
 async fn main() {
-    let run_params = get_run_params().await;
+    let run_params = match get_run_params().await {
+        Ok(run_params) => run_params,
+        Err(err) => panic!("{}", err),
+    };
 
     let subscriber = FmtSubscriber::builder()
         .with_max_level(tracing::Level::ERROR)
@@ -193,6 +342,89 @@ async fn main() {
     bale_proxy.run().await;
 }
 
+/// Asks for the login code if the login needs one, and returns the user id.
+async fn complete_login(
+    bale: &BaleClient,
+    run_params: &RunParams,
+    login_status: Result<LoginStatus, bale::Error>,
+) -> Result<u32, Error> {
+    match login_status? {
+        LoginStatus::LoggedIn(_jwt, user_id) => Ok(user_id),
+        LoginStatus::WaitingForValidationCode(_) => {
+            let login_code = get_login_code(run_params).await;
+            Ok(bale.validate_code(&login_code).await?.user_id)
+        }
+        LoginStatus::NotRegistered => Err(Error::InternalError(
+            "phone number is not registered".to_string(),
+        )),
+        LoginStatus::Error(err) => Err(Error::InternalError(err)),
+        _ => Err(Error::InternalError("unknown error happened".to_string())),
+    }
+}
+
+async fn get_login_code(run_params: &RunParams) -> String {
+    let message = format!(
+        "Please enter login code sent by sms to {}:",
+        run_params.phone_number.unwrap()
+    );
+    match &run_params.login_code_command {
+        Some(command) => {
+            info!("{} running {}", message, command);
+            get_input_from_command(command)
+        }
+        None => get_input(message, !run_params.running_from_shadowsocks).await,
+    }
+}
+
+/// Warns before the session expires, and logs in again whenever it is not
+/// logged in, until it succeeds. Tunnel sessions live on their own, so open
+/// connections survive this.
+async fn watch_login(client: Arc<BaleClient>, run_params: RunParams) {
+    let mut login_status = client.watch_login_status();
+    loop {
+        if !matches!(client.login_status(), LoginStatus::LoggedIn(..)) {
+            warn!("not logged in to Bale anymore, logging in again");
+            let login_status = client.login().await;
+            match complete_login(&client, &run_params, login_status).await {
+                Ok(_user_id) => info!("logged in again"),
+                Err(Error::BaleError(bale::Error::RateLimited(Some(delay)))) => {
+                    warn!("rate limited while logging in, retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => {
+                    error!("could not log in again : {}", err);
+                    tokio::time::sleep(RELOGIN_DELAY).await;
+                }
+            }
+            continue;
+        }
+
+        let expires_in = client
+            .token_expiry()
+            .map(|expiry| expiry.duration_since(SystemTime::now()).unwrap_or_default());
+        let wake_in = match expires_in {
+            Some(expires_in) if expires_in > EXPIRY_WARNING => expires_in - EXPIRY_WARNING,
+            Some(expires_in) => expires_in,
+            None => Duration::MAX,
+        };
+
+        select! {
+            res = login_status.changed() => {
+                if res.is_err() {
+                    return;
+                }
+            },
+            _ = tokio::time::sleep(wake_in), if expires_in.is_some() => {
+                match client.token_expiry().map(|expiry| expiry.duration_since(SystemTime::now())) {
+                    Some(Ok(expires_in)) => warn!("Bale session expires in {:?}", expires_in),
+                    Some(Err(_)) => client.expire(),
+                    None => {}
+                }
+            },
+        }
+    }
+}
+
 async fn get_input(message: String, has_terminal_access: bool) -> String {
     if has_terminal_access {
         get_input_from_terminal(message)
@@ -205,6 +437,18 @@ async fn get_input_from_http(message: String) -> String {
     get_from_web(message).await.unwrap()
 }
 
+fn get_input_from_command(command: &str) -> String {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .expect("could not run login code command");
+    if !output.status.success() {
+        error!("login code command failed : {}", output.status);
+    }
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
 fn get_input_from_terminal(message: String) -> String {
     let mut user_input = String::new();
     info!("{} ", message);
@@ -221,16 +465,55 @@ fn get_input_from_terminal(message: String) -> String {
     user_input.trim().to_string()
 }
 
+/// How long before the session expires to warn about it.
+const EXPIRY_WARNING: Duration = Duration::from_secs(24 * 60 * 60);
+/// Delay between attempts to log in again.
+#[cfg(not(test))]
+const RELOGIN_DELAY: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const RELOGIN_DELAY: Duration = Duration::from_millis(50);
+
+/// Frames read from sockets that can wait to be sent.
+const INBOUND_QUEUE_SIZE: usize = 256;
+/// Messages waiting in the outbox above which sockets are not read anymore.
+const MAX_QUEUED_MESSAGES: usize = 32;
+/// How often unacknowledged messages are checked for retransmission.
+#[cfg(not(test))]
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(500);
+#[cfg(test)]
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Documents are off by default: the file RPCs they rely on are not confirmed
+/// to work on Bale yet.
+const DEFAULT_DOCUMENT_THRESHOLD: usize = 0;
+
 #[derive(Debug, Clone)]
 struct RunParams {
     running_from_shadowsocks: bool,
     phone_number: Option<u64>,
     jwt: Option<String>,
-    remote_host: Option<String>,
-    remote_port: Option<String>,
+    credentials_dir: Option<PathBuf>,
+    login_code_command: Option<String>,
+    endpoint: Option<String>,
+    api_key: Option<String>,
+    client_version: Option<i32>,
+    user_agent: Option<String>,
+    root_certificate: Option<String>,
+    accept_invalid_certs: bool,
+    server_key: Option<String>,
+    private_key: Option<String>,
+    allowed_users: Vec<u32>,
+    codec: String,
+    document_threshold: usize,
+    rate_limit: f64,
+    auto_delete: bool,
+    socks5: Option<String>,
+    http_proxy: Option<String>,
+    udp: bool,
+    open_targets: bool,
+    peer: Option<Chat>,
     local_host: Option<String>,
     local_port: Option<String>,
-    opts: Option<String>,
     mode: OperationMode,
     log_level: tracing::Level,
 }
@@ -241,68 +524,499 @@ pub(crate) enum OperationMode {
     Client(u32),
 }
 
-async fn get_run_params() -> RunParams {
+async fn get_run_params() -> Result<RunParams, Error> {
     let remote_host: Option<String> = env::var("SS_REMOTE_HOST").ok();
     let remote_port: Option<String> = env::var("SS_REMOTE_PORT").ok();
     let local_host: Option<String> = env::var("SS_LOCAL_HOST").ok();
     let local_port: Option<String> = env::var("SS_LOCAL_PORT").ok();
-    let mut opts: Option<String> = env::var("SS_PLUGIN_OPTIONS").ok();
     let mut mode: OperationMode = OperationMode::Server;
     let mut log_level: tracing::Level = tracing::Level::INFO;
 
     let mut phone_number: Option<u64> = None;
     let mut jwt: Option<String> = None;
-    opts = opts.map(|opts| {
-        opts.split(';')
-            .map(|opt| opt.trim())
-            .filter(|opt| {
-                if opt.to_lowercase().starts_with("phone_number=") {
-                    phone_number = opt["phone_number=".len()..].parse().ok();
-                    return false;
-                } else if opt.to_lowercase().starts_with("jwt=") {
-                    jwt = Some((&opt["jwt=".len()..]).to_string());
-                    return false;
-                } else if opt.to_lowercase().starts_with("client=") {
-                    mode = OperationMode::Client(opt["client=".len()..].parse().unwrap());
-                    return false;
-                } else if opt.to_lowercase() == "server" {
-                    mode = OperationMode::Server;
-                    return false;
-                } else if opt.to_lowercase().starts_with("log_level=") {
-                    log_level = tracing::Level::from_str(&opt["log_level=".len()..])
-                        .unwrap_or(tracing::Level::INFO);
-                    return false;
+    let mut credentials_dir: Option<PathBuf> = None;
+    let mut login_code_command: Option<String> = None;
+    let mut endpoint: Option<String> = None;
+    let mut api_key: Option<String> = None;
+    let mut client_version: Option<i32> = None;
+    let mut user_agent: Option<String> = None;
+    let mut root_certificate: Option<String> = None;
+    let mut accept_invalid_certs = false;
+    let mut server_key: Option<String> = None;
+    let mut private_key: Option<String> = None;
+    let mut allowed_users: Vec<u32> = Vec::new();
+    let mut config_path: Option<String> = None;
+    let mut codec = codec::Base64.name().to_string();
+    let mut document_threshold = DEFAULT_DOCUMENT_THRESHOLD;
+    let mut rate_limit = RateLimit::default().per_second;
+    let mut auto_delete = false;
+    let mut socks5: Option<String> = None;
+    let mut http_proxy: Option<String> = None;
+    let mut udp = false;
+    let mut open_targets = false;
+    let mut peer: Option<Chat> = None;
+    if let Ok(opts) = env::var("SS_PLUGIN_OPTIONS") {
+        for opt in opts.split(';').map(|opt| opt.trim()) {
+            if opt.to_lowercase().starts_with("phone_number=") {
+                phone_number = opt["phone_number=".len()..].parse().ok();
+            } else if opt.to_lowercase().starts_with("jwt=") {
+                jwt = Some(opt["jwt=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("credentials_dir=") {
+                credentials_dir = Some(PathBuf::from(&opt["credentials_dir=".len()..]));
+            } else if opt.to_lowercase().starts_with("login_code_command=") {
+                login_code_command = Some(opt["login_code_command=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("endpoint=") {
+                endpoint = Some(opt["endpoint=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("api_key=") {
+                api_key = Some(opt["api_key=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("client_version=") {
+                client_version = Some(
+                    opt["client_version=".len()..]
+                        .parse()
+                        .expect("bad client version"),
+                );
+            } else if opt.to_lowercase().starts_with("user_agent=") {
+                user_agent = Some(opt["user_agent=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("root_certificate=") {
+                root_certificate = Some(opt["root_certificate=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("accept_invalid_certs=") {
+                accept_invalid_certs = opt["accept_invalid_certs=".len()..]
+                    .parse()
+                    .expect("accept_invalid_certs must be true or false");
+            } else if opt.to_lowercase().starts_with("server_key=") {
+                server_key = Some(opt["server_key=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("private_key=") {
+                private_key = Some(opt["private_key=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("allowed_users=") {
+                allowed_users.extend(
+                    opt["allowed_users=".len()..]
+                        .split(',')
+                        .filter(|id| !id.trim().is_empty())
+                        .map(|id| id.trim().parse::<u32>().expect("bad allowed user id")),
+                );
+            } else if opt.to_lowercase().starts_with("config=") {
+                config_path = Some(opt["config=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("codec=") {
+                codec = opt["codec=".len()..].to_string();
+                if codec::codec_by_name(&codec).is_none() {
+                    panic!("unknown codec {}", codec);
                 }
-                true
-            })
-            .collect::<Vec<&str>>()
-            .join(";")
-    });
+            } else if opt.to_lowercase().starts_with("document_threshold=") {
+                document_threshold = opt["document_threshold=".len()..]
+                    .parse()
+                    .expect("bad document threshold");
+            } else if opt.to_lowercase().starts_with("rate_limit=") {
+                rate_limit = parse_rate_limit(&opt["rate_limit=".len()..])?;
+            } else if opt.to_lowercase().starts_with("auto_delete=") {
+                auto_delete = opt["auto_delete=".len()..]
+                    .parse()
+                    .expect("auto_delete must be true or false");
+            } else if opt.to_lowercase().starts_with("socks5=") {
+                socks5 = Some(opt["socks5=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("http_proxy=") {
+                http_proxy = Some(opt["http_proxy=".len()..].to_string());
+            } else if opt.to_lowercase().starts_with("udp=") {
+                udp = opt["udp=".len()..]
+                    .parse()
+                    .expect("udp must be true or false");
+            } else if opt.to_lowercase().starts_with("open_targets=") {
+                open_targets = opt["open_targets=".len()..]
+                    .parse()
+                    .expect("open_targets must be true or false");
+            } else if opt.to_lowercase().starts_with("peer=") {
+                let chat: bale::Peer = opt["peer=".len()..].parse().expect("bad peer");
+                peer = Some(chat.into());
+            } else if opt.to_lowercase().starts_with("client=") {
+                mode = OperationMode::Client(opt["client=".len()..].parse().unwrap());
+            } else if opt.to_lowercase() == "server" {
+                mode = OperationMode::Server;
+            } else if opt.to_lowercase().starts_with("log_level=") {
+                log_level = tracing::Level::from_str(&opt["log_level=".len()..])
+                    .unwrap_or(tracing::Level::INFO);
+            }
+        }
+    }
+
+    let credentials_dir = credentials_dir
+        .or_else(|| CredentialStore::default_location().map(|store| store.dir().to_path_buf()));
+
+    let config = config_path
+        .map(|path| Config::load(path).expect("could not load config file"))
+        .unwrap_or_default();
+    allowed_users.extend(config.server.allowed_users);
 
     let running_from_shadowsocks = matches!(
         (&remote_host, &remote_port, &local_host, &local_port),
         (Some(_), Some(_), Some(_), Some(_))
     );
 
-    if phone_number == None {
-        let phone_number_str = get_input(
-            "Please enter mobile number (can be from receive-smss.com):".to_string(),
-            !running_from_shadowsocks,
-        )
-        .await;
-        phone_number = phone_number_str.parse().ok()
-    }
-
-    RunParams {
+    let mut run_params = RunParams {
         running_from_shadowsocks,
         phone_number,
         jwt,
-        remote_host,
-        remote_port,
+        credentials_dir,
+        login_code_command,
+        endpoint,
+        api_key,
+        client_version,
+        user_agent,
+        root_certificate,
+        accept_invalid_certs,
+        server_key,
+        private_key,
+        allowed_users,
+        codec,
+        document_threshold,
+        rate_limit,
+        auto_delete,
+        socks5,
+        http_proxy,
+        udp,
+        open_targets,
+        peer,
         local_host,
         local_port,
-        opts,
         mode,
         log_level,
+    };
+    check_run_params(&run_params)?;
+
+    if run_params.phone_number.is_none() {
+        let phone_number_str = get_input(
+            "Please enter mobile number (can be from receive-smss.com):".to_string(),
+            !running_from_shadowsocks,
+        )
+        .await;
+        run_params.phone_number = phone_number_str.parse().ok()
+    }
+
+    Ok(run_params)
+}
+
+/// Parses messages per second, which must be a positive number.
+fn parse_rate_limit(value: &str) -> Result<f64, Error> {
+    match value.parse::<f64>() {
+        Ok(rate_limit) if rate_limit.is_finite() && rate_limit > 0.0 => Ok(rate_limit),
+        _ => Err(Error::InvalidSettings(format!(
+            "rate_limit must be a positive number of messages per second, not {}",
+            value
+        ))),
+    }
+}
+
+/// Checks options that depend on each other.
+fn check_run_params(run_params: &RunParams) -> Result<(), Error> {
+    if let OperationMode::Server = run_params.mode {
+        if run_params.private_key.is_none() && run_params.credentials_dir.is_none() {
+            return Err(Error::InvalidSettings(
+                "there is nowhere to keep the server key, set private_key= or credentials_dir="
+                    .to_string(),
+            ));
+        }
+        if run_params.open_targets && run_params.allowed_users.is_empty() {
+            return Err(Error::InvalidSettings(
+                "open_targets lets clients reach any address the server can, set allowed_users= too"
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{Conditions, Network};
+    use async_std::net::{Shutdown, TcpListener, TcpStream};
+    use bale_mock::MockBale;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use proptest::prelude::*;
+    use std::net::SocketAddr;
+
+    const SERVER_ID: u32 = 1;
+    const CLIENT_ID: u32 = 2;
+    /// Low enough that the bigger test payloads go out as documents.
+    const DOCUMENT_THRESHOLD: usize = 32 * 1024;
+    const REPLICA_ID: u32 = 3;
+    const GROUP_ID: u32 = 10;
+
+    /// User id of an account and the messenger it talks through.
+    type Account = (u32, Arc<dyn MessengerTransport>);
+
+    fn loopback(network: &Arc<Network>, user_id: u32) -> Account {
+        (user_id, Arc::new(network.join(user_id)))
+    }
+
+    fn run_params(mode: OperationMode, local_addr: SocketAddr) -> RunParams {
+        RunParams {
+            running_from_shadowsocks: true,
+            phone_number: None,
+            jwt: None,
+            credentials_dir: None,
+            login_code_command: None,
+            endpoint: None,
+            api_key: None,
+            client_version: None,
+            user_agent: None,
+            root_certificate: None,
+            accept_invalid_certs: false,
+            server_key: None,
+            private_key: None,
+            allowed_users: vec![CLIENT_ID],
+            codec: codec::Base64.name().to_string(),
+            document_threshold: DEFAULT_DOCUMENT_THRESHOLD,
+            rate_limit: RateLimit::default().per_second,
+            auto_delete: false,
+            socks5: None,
+            http_proxy: None,
+            udp: false,
+            open_targets: false,
+            peer: None,
+            local_host: Some(local_addr.ip().to_string()),
+            local_port: Some(local_addr.port().to_string()),
+            mode,
+            log_level: tracing::Level::INFO,
+        }
+    }
+
+    /// Sends `data` through `client` and the first of `servers` talking in
+    /// `chat`, to an upstream that sends it back. The servers share their key.
+    async fn echo(
+        servers: Vec<Account>,
+        client: Account,
+        chat: Option<Chat>,
+        document_threshold: usize,
+        data: Vec<u8>,
+    ) -> Vec<u8> {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            async_std::io::copy(&mut &stream, &mut &stream)
+                .await
+                .unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+        });
+        let client_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let static_key = crypto::generate_static_key();
+        let server_key = PublicKey::from(&static_key);
+        let (client_id, client) = client;
+        let server_id = servers[0].0;
+        for (server_id, server) in servers {
+            let server_params = RunParams {
+                allowed_users: vec![client_id],
+                document_threshold,
+                ..run_params(OperationMode::Server, upstream_addr)
+            };
+            tokio::spawn(BaleProxy::run_tunnel(
+                server,
+                server_id,
+                server_params,
+                Some(static_key.clone()),
+                None,
+            ));
+        }
+        let client_params = RunParams {
+            peer: chat,
+            document_threshold,
+            ..run_params(OperationMode::Client(server_id), client_addr)
+        };
+        tokio::spawn(BaleProxy::run_tunnel(
+            client,
+            client_id,
+            client_params,
+            None,
+            Some(server_key),
+        ));
+
+        let stream = loop {
+            match TcpStream::connect(client_addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut writer = stream.clone();
+        async_std::task::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+        let mut echoed = Vec::new();
+        (&stream).read_to_end(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn carries_bytes_unchanged(
+            data in proptest::collection::vec(any::<u8>(), 1..40_000),
+            latency in 0..20u64,
+            reordering in 0..20u64,
+            duplication in 0.0..0.3,
+            loss in 0.0..0.2,
+            seed in any::<u64>(),
+        ) {
+            let conditions = Conditions {
+                latency: Duration::from_millis(latency),
+                reordering: Duration::from_millis(reordering),
+                duplication,
+                loss,
+            };
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let echoed = runtime.block_on(async {
+                let network = Network::new(conditions, seed);
+                let echo = echo(
+                    vec![loopback(&network, SERVER_ID)],
+                    loopback(&network, CLIENT_ID),
+                    None,
+                    DOCUMENT_THRESHOLD,
+                    data.clone(),
+                );
+                tokio::time::timeout(Duration::from_secs(60), echo)
+                    .await
+                    .expect("tunnel stalled")
+            });
+            prop_assert_eq!(echoed, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn carries_bytes_through_a_group() {
+        let network = Network::new(Conditions::default(), 0);
+        let group = network.create_group(GROUP_ID, &[SERVER_ID, CLIENT_ID, REPLICA_ID]);
+        let data: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+
+        // The replica shares the key and reads everything sent in the group.
+        let echo = echo(
+            vec![
+                loopback(&network, SERVER_ID),
+                loopback(&network, REPLICA_ID),
+            ],
+            loopback(&network, CLIENT_ID),
+            Some(group),
+            DOCUMENT_THRESHOLD,
+            data.clone(),
+        );
+        let echoed = tokio::time::timeout(Duration::from_secs(30), echo)
+            .await
+            .expect("tunnel stalled");
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn carries_bytes_over_bale() {
+        let bale = MockBale::start().unwrap();
+        let mut accounts = Vec::new();
+        for phone_number in [989_120_000_001, 989_120_000_002] {
+            let user_id = bale.register(phone_number);
+            let client = BaleClient::builder(phone_number)
+                .endpoint(bale.url())
+                .build()
+                .unwrap()
+                .with_rate_limit(RateLimit {
+                    per_second: 100.0,
+                    burst: 100,
+                });
+            client.login_with(bale.login(user_id)).await.unwrap();
+            accounts.push((user_id, Arc::new(client) as Arc<dyn MessengerTransport>));
+        }
+        let client = accounts.pop().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+
+        // A low threshold sends most of it as documents.
+        let echo = echo(accounts, client, None, 1024, data.clone());
+        let echoed = tokio::time::timeout(Duration::from_secs(30), echo)
+            .await
+            .expect("tunnel stalled");
+        assert_eq!(echoed, data);
+        assert!(bale.uploads() > 0);
+    }
+
+    #[tokio::test]
+    async fn logs_in_again_after_a_failed_attempt() {
+        let bale = MockBale::start().unwrap();
+        let phone_number = 989_120_000_001;
+        let user_id = bale.register(phone_number);
+        let client = BaleClient::builder(phone_number)
+            .endpoint(bale.url())
+            .build()
+            .unwrap();
+        client.login_with(bale.login(user_id)).await.unwrap();
+        let client = Arc::new(client);
+
+        // The first code is wrong, the next ones are right.
+        let attempted = env::temp_dir().join(format!("bale-proxy-login-{}", rand::random::<u64>()));
+        let run_params = RunParams {
+            phone_number: Some(phone_number),
+            login_code_command: Some(format!(
+                "if [ -e {0} ]; then echo {1}; else touch {0}; echo 0; fi",
+                attempted.display(),
+                bale_mock::LOGIN_CODE
+            )),
+            ..run_params(OperationMode::Server, "127.0.0.1:0".parse().unwrap())
+        };
+        tokio::spawn(watch_login(client.clone(), run_params));
+
+        bale.expire_sessions();
+        let hello = || client.send_message(bale::Peer::User(user_id), "hello".to_string());
+        assert!(hello().await.is_err());
+        let mut login_status = client.watch_login_status();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(*login_status.borrow_and_update(), LoginStatus::LoggedIn(..)) {
+                login_status.changed().await.unwrap();
+            }
+        })
+        .await
+        .expect("did not log in again");
+
+        assert!(attempted.exists());
+        hello().await.unwrap();
+        std::fs::remove_file(attempted).unwrap();
+    }
+
+    #[test]
+    fn refuses_open_targets_without_allowed_users() {
+        let mut run_params = run_params(OperationMode::Server, "127.0.0.1:8388".parse().unwrap());
+        run_params.private_key = Some(base64::encode(crypto::generate_static_key().to_bytes()));
+        run_params.open_targets = true;
+        assert!(check_run_params(&run_params).is_ok());
+
+        run_params.allowed_users.clear();
+        assert!(matches!(
+            check_run_params(&run_params),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_rate_limits_that_never_send() {
+        assert_eq!(parse_rate_limit("0.5").unwrap(), 0.5);
+        for value in ["0", "-1", "NaN", "inf", "fast"].iter() {
+            assert!(matches!(
+                parse_rate_limit(value),
+                Err(Error::InvalidSettings(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn asks_where_to_keep_the_server_key() {
+        let mut run_params = run_params(OperationMode::Server, "127.0.0.1:8388".parse().unwrap());
+        assert!(matches!(
+            check_run_params(&run_params),
+            Err(Error::InvalidSettings(_))
+        ));
+
+        run_params.credentials_dir = Some(std::env::temp_dir());
+        assert!(check_run_params(&run_params).is_ok());
+
+        run_params.credentials_dir = None;
+        run_params.private_key = Some(base64::encode(crypto::generate_static_key().to_bytes()));
+        assert!(check_run_params(&run_params).is_ok());
     }
 }
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use crate::framing::SEQUENCE_SIZE;
+
+/// Messages sent but not acknowledged yet, beyond which new ones wait.
+pub(crate) const SEND_WINDOW: usize = 64;
+
+#[cfg(not(test))]
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(15);
+// Tests run over a loopback transport that delivers in milliseconds.
+#[cfg(test)]
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(300);
+const MAX_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Message packed by a [`crate::framing::Packer`], and how it is sent.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Packed {
+    pub(crate) message: Vec<u8>,
+    pub(crate) document: bool,
+}
+
+impl Packed {
+    fn sequence(&self) -> u64 {
+        u64::from_be_bytes(self.message[..SEQUENCE_SIZE].try_into().unwrap())
+    }
+}
+
+struct InFlight {
+    packed: Packed,
+    sent_at: Instant,
+    timeout: Duration,
+    attempts: u32,
+}
+
+/// Keeps sent messages until the peer acknowledges them and sends them again
+/// if it doesn't in time, since the messenger may silently drop some.
+///
+/// Acknowledgements are cumulative: the peer tells the sequence number of the
+/// first message it is still missing. At most [`SEND_WINDOW`] messages are in
+/// flight, so a slow peer doesn't get flooded with messages it can't buffer.
+pub(crate) struct Retransmitter {
+    in_flight: BTreeMap<u64, InFlight>,
+    waiting: VecDeque<Packed>,
+}
+
+impl Retransmitter {
+    pub(crate) fn new() -> Retransmitter {
+        Retransmitter {
+            in_flight: BTreeMap::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// Returns the messages that can be sent now, in order.
+    pub(crate) fn push(&mut self, messages: Vec<Packed>, now: Instant) -> Vec<Packed> {
+        self.waiting.extend(messages);
+        self.send_waiting(now)
+    }
+
+    /// Forgets the messages before `ack` and returns the ones that can be sent
+    /// now that the window moved.
+    pub(crate) fn ack(&mut self, ack: u64, now: Instant) -> Vec<Packed> {
+        self.in_flight = self.in_flight.split_off(&ack);
+        self.send_waiting(now)
+    }
+
+    /// Returns the messages that were not acknowledged in time, to be sent again.
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<Packed> {
+        self.in_flight
+            .values_mut()
+            .filter(|in_flight| now.duration_since(in_flight.sent_at) >= in_flight.timeout)
+            .map(|in_flight| {
+                in_flight.sent_at = now;
+                in_flight.timeout = (in_flight.timeout * 2).min(MAX_TIMEOUT);
+                in_flight.attempts += 1;
+                in_flight.packed.clone()
+            })
+            .collect()
+    }
+
+    /// Whether a message was sent again `attempts` times and still not acknowledged.
+    pub(crate) fn is_stalled(&self, attempts: u32) -> bool {
+        self.in_flight
+            .values()
+            .any(|in_flight| in_flight.attempts >= attempts)
+    }
+
+    fn send_waiting(&mut self, now: Instant) -> Vec<Packed> {
+        let mut ready = Vec::new();
+        while self.in_flight.len() < SEND_WINDOW {
+            let packed = match self.waiting.pop_front() {
+                Some(packed) => packed,
+                None => break,
+            };
+            self.in_flight.insert(
+                packed.sequence(),
+                InFlight {
+                    packed: packed.clone(),
+                    sent_at: now,
+                    timeout: INITIAL_TIMEOUT,
+                    attempts: 0,
+                },
+            );
+            ready.push(packed);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed(sequence: u64) -> Packed {
+        Packed {
+            message: sequence.to_be_bytes().to_vec(),
+            document: false,
+        }
+    }
+
+    #[test]
+    fn sends_again_until_acknowledged() {
+        let start = Instant::now();
+        let mut retransmitter = Retransmitter::new();
+        assert_eq!(
+            retransmitter.push(vec![packed(0), packed(1)], start),
+            vec![packed(0), packed(1)]
+        );
+        assert!(retransmitter.due(start + INITIAL_TIMEOUT / 2).is_empty());
+
+        assert!(retransmitter.ack(1, start).is_empty());
+        assert_eq!(retransmitter.due(start + INITIAL_TIMEOUT), vec![packed(1)]);
+        // The timeout doubles after each attempt.
+        assert!(retransmitter.due(start + INITIAL_TIMEOUT * 2).is_empty());
+        assert_eq!(
+            retransmitter.due(start + INITIAL_TIMEOUT * 3),
+            vec![packed(1)]
+        );
+
+        assert!(retransmitter.is_stalled(2));
+        assert!(!retransmitter.is_stalled(3));
+
+        retransmitter.ack(2, start);
+        assert!(retransmitter.due(start + MAX_TIMEOUT * 10).is_empty());
+        assert!(!retransmitter.is_stalled(1));
+    }
+
+    #[test]
+    fn keeps_messages_beyond_window() {
+        let now = Instant::now();
+        let mut retransmitter = Retransmitter::new();
+        let messages = (0..SEND_WINDOW as u64 + 2).map(packed).collect();
+        assert_eq!(retransmitter.push(messages, now).len(), SEND_WINDOW);
+
+        assert_eq!(
+            retransmitter.ack(2, now),
+            vec![packed(SEND_WINDOW as u64), packed(SEND_WINDOW as u64 + 1)]
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::codec::{codec_by_name, Base64, PayloadCodec};
+use crate::crypto::{self, Cipher, ClientHandshake, SEAL_OVERHEAD};
+use crate::error::Error;
+use crate::frame::Frame;
+use crate::framing::{Packer, Reassembler, SEQUENCE_SIZE};
+use crate::reliability::{Packed, Retransmitter};
+use crate::tunnel::MAX_MENTION_LENGTH;
+
+/// Largest sealed packet sent as a single document.
+const MAX_DOCUMENT_SIZE: usize = 1024 * 1024;
+
+// Cumulative acknowledgement at the start of every sealed packet.
+const ACK_SIZE: usize = 8;
+// How long an acknowledgement waits for a message to travel with.
+#[cfg(not(test))]
+const ACK_DELAY: Duration = Duration::from_secs(2);
+#[cfg(test)]
+const ACK_DELAY: Duration = Duration::from_millis(50);
+// How long the client waits for the server hello before sending its hello again.
+#[cfg(not(test))]
+const HELLO_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const HELLO_TIMEOUT: Duration = Duration::from_millis(500);
+// How many times the client sends a message again before it assumes the server
+// lost the session, about four minutes.
+const RESTART_ATTEMPTS: u32 = 4;
+
+/// Message to send to the peer.
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    /// Encoded packet, sent as a text message.
+    Text(String),
+    /// Raw packet, uploaded and sent as a document.
+    Document(Vec<u8>),
+}
+
+/// Encrypted tunnel with a single peer.
+///
+/// Handshake packets are always encoded with base64. The client proposes the
+/// codecs it would like to use for the rest of the session and the server
+/// picks one. Frames sent before the handshake is done are kept and sealed as
+/// soon as the session is established.
+///
+/// Batches of frames bigger than `document_threshold` bytes are sent as
+/// documents instead of many text messages, since one upload is much cheaper
+/// than hundreds of messages. Zero disables documents.
+///
+/// Every sealed packet starts with the sequence number of the first message
+/// not received yet, which acknowledges the ones before it. Messages that are
+/// not acknowledged in time are sent again, see [`Session::tick`]. A client
+/// whose messages stay unacknowledged starts over, see [`Session::restart`].
+pub(crate) struct Session {
+    state: State,
+    // Server key and codec the client connected with, to start over.
+    server: Option<(PublicKey, &'static dyn PayloadCodec)>,
+    codec: &'static dyn PayloadCodec,
+    document_threshold: usize,
+    hello: String,
+    hello_sent_at: Instant,
+    pending: Vec<Frame>,
+    packer: Packer,
+    reassembler: Reassembler,
+    retransmitter: Retransmitter,
+    ack_due: Option<Instant>,
+    // Acknowledgement carried by the last packet sent.
+    ack_sent: u64,
+    // Highest sequence number of the messages opened so far.
+    last_opened: Option<u64>,
+}
+
+enum State {
+    Handshaking(ClientHandshake),
+    Established(Cipher, Option<PublicKey>),
+}
+
+impl Session {
+    fn new(
+        state: State,
+        codec: &'static dyn PayloadCodec,
+        document_threshold: usize,
+        hello: String,
+    ) -> Session {
+        Session {
+            state,
+            server: None,
+            codec,
+            document_threshold,
+            hello,
+            hello_sent_at: Instant::now(),
+            pending: Vec::new(),
+            packer: packer_for(codec),
+            reassembler: Reassembler::new(),
+            retransmitter: Retransmitter::new(),
+            ack_due: None,
+            ack_sent: 0,
+            last_opened: None,
+        }
+    }
+
+    /// Starts a session with a server, proposing `codec`. Returns the session
+    /// and the client hello.
+    pub(crate) fn connect(
+        server_key: PublicKey,
+        codec: &'static dyn PayloadCodec,
+        document_threshold: usize,
+    ) -> (Session, String) {
+        let mut proposal = vec![codec.name()];
+        if codec.name() != Base64.name() {
+            proposal.push(Base64.name());
+        }
+
+        let (handshake, hello) = ClientHandshake::start(server_key, proposal.join(",").as_bytes());
+        let hello = Base64.encode(&hello);
+        let mut session = Session::new(
+            State::Handshaking(handshake),
+            &Base64,
+            document_threshold,
+            hello.clone(),
+        );
+        session.server = Some((server_key, codec));
+        (session, hello)
+    }
+
+    /// Starts a new session with the server if it stopped acknowledging
+    /// messages, most likely because it restarted and lost this one. Streams
+    /// of the old session are lost, the tunnel resets their local side.
+    /// Returns the new session and its hello.
+    pub(crate) fn restart(&self) -> Option<(Session, String)> {
+        let (server_key, codec) = self.server?;
+        if !matches!(self.state, State::Established(..))
+            || !self.retransmitter.is_stalled(RESTART_ATTEMPTS)
+        {
+            return None;
+        }
+        Some(Session::connect(server_key, codec, self.document_threshold))
+    }
+
+    /// Accepts a session from a client, picking the first codec it proposed
+    /// that is supported. Returns the session and the server hello.
+    pub(crate) fn accept(
+        static_key: &StaticSecret,
+        client_key: PublicKey,
+        proposal: &[u8],
+        document_threshold: usize,
+    ) -> (Session, String) {
+        let codec = String::from_utf8_lossy(proposal)
+            .split(',')
+            .find_map(codec_by_name)
+            .unwrap_or(&Base64);
+
+        let (cipher, hello) =
+            crypto::accept(static_key, client_key, proposal, codec.name().as_bytes());
+        let hello = Base64.encode(&hello);
+        (
+            Session::new(
+                State::Established(cipher, Some(client_key)),
+                codec,
+                document_threshold,
+                hello.clone(),
+            ),
+            hello,
+        )
+    }
+
+    /// Completes the handshake with the server hello and returns the frames
+    /// that were waiting for it, sealed.
+    pub(crate) fn finish(
+        &mut self,
+        server_key: PublicKey,
+        confirmation: &[u8],
+    ) -> Result<Vec<Outgoing>, Error> {
+        let (cipher, codec_name) = match &self.state {
+            State::Handshaking(handshake) => handshake.finish(server_key, confirmation)?,
+            State::Established(..) => {
+                return Err(Error::CryptoError(
+                    "session is already established".to_string(),
+                ))
+            }
+        };
+        let codec = codec_by_name(&String::from_utf8_lossy(&codec_name))
+            .ok_or_else(|| Error::CryptoError("server picked an unsupported codec".to_string()))?;
+
+        self.state = State::Established(cipher, None);
+        self.codec = codec;
+        self.packer = packer_for(codec);
+
+        let pending = std::mem::take(&mut self.pending);
+        self.seal(pending)
+    }
+
+    pub(crate) fn codec(&self) -> &'static dyn PayloadCodec {
+        self.codec
+    }
+
+    /// The hello this side sent to establish the session.
+    pub(crate) fn hello(&self) -> &str {
+        &self.hello
+    }
+
+    /// Whether the session was accepted from a client hello with `client_key`,
+    /// in which case the client is sending its hello again.
+    pub(crate) fn is_accepted_from(&self, client_key: &PublicKey) -> bool {
+        matches!(&self.state, State::Established(_, Some(key)) if key == client_key)
+    }
+
+    /// Decodes a message sent by the peer, which is either a sealed packet in
+    /// the session codec or a handshake packet.
+    pub(crate) fn decode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        self.codec.decode(text).or_else(|_| Base64.decode(text))
+    }
+
+    /// Packs, seals and encodes frames, or keeps them for later if the
+    /// handshake is not done yet. Fails without sending any of them if one
+    /// is too long for a message.
+    pub(crate) fn seal(&mut self, frames: Vec<Frame>) -> Result<Vec<Outgoing>, Error> {
+        if let State::Handshaking(_) = self.state {
+            self.packer.check(&frames)?;
+            self.pending.extend(frames);
+            return Ok(Vec::new());
+        }
+
+        let size: usize = frames.iter().map(Frame::encoded_len).sum();
+        let document = self.document_threshold > 0 && size > self.document_threshold;
+        let messages = if document {
+            self.packer
+                .pack_within(frames, MAX_DOCUMENT_SIZE - SEAL_OVERHEAD - ACK_SIZE)?
+        } else {
+            self.packer.pack(frames)?
+        };
+
+        let packed = messages
+            .into_iter()
+            .map(|message| Packed { message, document })
+            .collect();
+        let ready = self.retransmitter.push(packed, Instant::now());
+        Ok(self.send(ready))
+    }
+
+    /// Opens a packet and returns the frames that are now ready, in order,
+    /// along with the messages its acknowledgement let through.
+    pub(crate) fn open(&mut self, packet: &[u8]) -> Result<(Vec<Frame>, Vec<Outgoing>), Error> {
+        let plaintext = match &mut self.state {
+            State::Handshaking(_) => {
+                return Err(Error::CryptoError(
+                    "received sealed packet before handshake".to_string(),
+                ))
+            }
+            State::Established(cipher, _) => cipher.open(packet)?,
+        };
+        if plaintext.len() < ACK_SIZE {
+            return Err(Error::InvalidFrame(
+                "packet has no acknowledgement".to_string(),
+            ));
+        }
+
+        let now = Instant::now();
+        let ack = u64::from_be_bytes(plaintext[..ACK_SIZE].try_into().unwrap());
+        let ready = self.retransmitter.ack(ack, now);
+
+        // Packets with nothing but an acknowledgement are not acknowledged.
+        let message = &plaintext[ACK_SIZE..];
+        let frames = if message.is_empty() {
+            Vec::new()
+        } else {
+            self.ack_due.get_or_insert(now + ACK_DELAY);
+            let frames = self.reassembler.push(message)?;
+            let sequence = u64::from_be_bytes(message[..SEQUENCE_SIZE].try_into().unwrap());
+            self.last_opened = self.last_opened.max(Some(sequence));
+            frames
+        };
+        Ok((frames, self.send(ready)))
+    }
+
+    /// Highest sequence number of the messages opened so far, if any.
+    pub(crate) fn last_opened(&self) -> Option<u64> {
+        self.last_opened
+    }
+
+    /// Whether the message numbered `sequence` was acknowledged to the peer.
+    pub(crate) fn is_acknowledged(&self, sequence: u64) -> bool {
+        sequence < self.ack_sent
+    }
+
+    /// Returns the messages to send again because they were not acknowledged
+    /// in time, and the acknowledgement if nothing carried it yet.
+    pub(crate) fn tick(&mut self, now: Instant) -> Vec<Outgoing> {
+        if let State::Handshaking(_) = self.state {
+            if now.duration_since(self.hello_sent_at) < HELLO_TIMEOUT {
+                return Vec::new();
+            }
+            self.hello_sent_at = now;
+            return vec![Outgoing::Text(self.hello.clone())];
+        }
+
+        let due = self.retransmitter.due(now);
+        let mut outgoing = self.send(due);
+        if matches!(self.ack_due, Some(ack_due) if now >= ack_due) {
+            outgoing.extend(self.send(vec![Packed {
+                message: Vec::new(),
+                document: false,
+            }]));
+        }
+        outgoing
+    }
+
+    /// Seals packed messages along with the current acknowledgement.
+    fn send(&mut self, ready: Vec<Packed>) -> Vec<Outgoing> {
+        let cipher = match &mut self.state {
+            State::Handshaking(_) => return Vec::new(),
+            State::Established(cipher, _) => cipher,
+        };
+        if !ready.is_empty() {
+            self.ack_due = None;
+            self.ack_sent = self.reassembler.next_sequence();
+        }
+
+        let codec = self.codec;
+        let ack = self.reassembler.next_sequence().to_be_bytes();
+        ready
+            .into_iter()
+            .map(|packed| {
+                let mut plaintext = Vec::with_capacity(ACK_SIZE + packed.message.len());
+                plaintext.extend_from_slice(&ack);
+                plaintext.extend_from_slice(&packed.message);
+
+                let sealed = cipher.seal(&plaintext);
+                if packed.document {
+                    Outgoing::Document(sealed)
+                } else {
+                    Outgoing::Text(codec.encode(&sealed))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Packs messages that still fit in a text message once sealed, encoded and
+/// given a mention of their recipient.
+fn packer_for(codec: &dyn PayloadCodec) -> Packer {
+    let chars = bale::MAX_TEXT_MESSAGE_LENGTH - MAX_MENTION_LENGTH;
+    Packer::new(codec.capacity(chars) - SEAL_OVERHEAD - ACK_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Packet;
+
+    fn packet(outgoing: &Outgoing, codec: &dyn PayloadCodec) -> Vec<u8> {
+        match outgoing {
+            Outgoing::Text(text) => codec.decode(text).unwrap(),
+            Outgoing::Document(bytes) => bytes.clone(),
+        }
+    }
+
+    fn open(session: &mut Session, outgoing: &Outgoing) -> (Vec<Frame>, Vec<Outgoing>) {
+        let bytes = packet(outgoing, session.codec());
+        match Packet::parse(&bytes).unwrap() {
+            Packet::Sealed(sealed) => session.open(sealed).unwrap(),
+            _ => panic!("expected a sealed packet"),
+        }
+    }
+
+    fn established() -> (Session, Session) {
+        let static_key = crypto::generate_static_key();
+        let (mut client, hello) = Session::connect(PublicKey::from(&static_key), &Base64, 0);
+
+        let hello = Base64.decode(&hello).unwrap();
+        let (server, reply) = match Packet::parse(&hello).unwrap() {
+            Packet::ClientHello(key, proposal) => Session::accept(&static_key, key, proposal, 0),
+            _ => panic!("expected a client hello"),
+        };
+
+        let reply = Base64.decode(&reply).unwrap();
+        match Packet::parse(&reply).unwrap() {
+            Packet::ServerHello(key, confirmation) => {
+                assert!(client.finish(key, confirmation).unwrap().is_empty())
+            }
+            _ => panic!("expected a server hello"),
+        }
+        (client, server)
+    }
+
+    #[test]
+    fn sends_lost_messages_again() {
+        let (mut client, mut server) = established();
+        let open_frame = Frame::Open { stream_id: 1 };
+
+        // The first message is lost on the way.
+        let lost = client.seal(vec![open_frame.clone()]).unwrap();
+        assert_eq!(lost.len(), 1);
+        let later = Instant::now() + Duration::from_secs(3600);
+
+        let again = client.tick(later);
+        assert_eq!(again.len(), 1);
+        let (frames, ready) = open(&mut server, &again[0]);
+        assert_eq!(frames, vec![open_frame]);
+        assert!(ready.is_empty());
+
+        // The server acknowledges it once nothing else carried the ack.
+        let ack = server.tick(later);
+        assert_eq!(ack.len(), 1);
+        assert!(open(&mut client, &ack[0]).0.is_empty());
+        assert!(client.tick(later + ACK_DELAY).is_empty());
+        assert!(server.tick(later + ACK_DELAY).is_empty());
+    }
+
+    #[test]
+    fn client_restarts_unacknowledged_session() {
+        let (mut client, server) = established();
+        assert!(server.restart().is_none());
+
+        assert_eq!(
+            client
+                .seal(vec![Frame::Open { stream_id: 1 }])
+                .unwrap()
+                .len(),
+            1
+        );
+        let mut later = Instant::now();
+        for _ in 0..RESTART_ATTEMPTS {
+            assert!(client.restart().is_none());
+            later += Duration::from_secs(3600);
+            assert_eq!(client.tick(later).len(), 1);
+        }
+
+        let (restarted, hello) = client.restart().unwrap();
+        assert!(matches!(restarted.state, State::Handshaking(_)));
+        let hello = Base64.decode(&hello).unwrap();
+        assert!(matches!(
+            Packet::parse(&hello).unwrap(),
+            Packet::ClientHello(..)
+        ));
+    }
+
+    #[test]
+    fn client_sends_hello_again() {
+        let static_key = crypto::generate_static_key();
+        let (mut client, hello) = Session::connect(PublicKey::from(&static_key), &Base64, 0);
+        assert!(client
+            .seal(vec![Frame::Open { stream_id: 1 }])
+            .unwrap()
+            .is_empty());
+
+        assert!(client.tick(Instant::now()).is_empty());
+        match client.tick(Instant::now() + HELLO_TIMEOUT).as_slice() {
+            [Outgoing::Text(again)] => assert_eq!(again, &hello),
+            other => panic!("expected the hello again, got {:?}", other),
+        }
+    }
+}
@@ -10,7 +10,7 @@ use url::Url;
 use crate::error::Error;
 use std::collections::HashMap;
 
-const TEMPLATE: &'static str = include_str!("../template/input.html");
+const TEMPLATE: &str = include_str!("../template/input.html");
 
 pub async fn get_from_web(message: String) -> Result<String, Error> {
     // Open up a TCP connection and create a URL.
@@ -31,7 +31,6 @@ pub async fn get_from_web(message: String) -> Result<String, Error> {
                 match accept(stream, msg).await {
                     Ok(res) => {
                         let _ = tx.send(res).await;
-                        return;
                     }
                     Err(Error::ServerError(_)) => {}
                     Err(err) => error!("{}", err),
@@ -58,8 +57,7 @@ async fn accept(mut stream: TcpStream, title: String) -> Result<String, Error> {
     }
     let path = RE
         .captures(&req_str)
-        .map(|captures| captures.get(1).map(|m| m.as_str()))
-        .flatten()
+        .and_then(|captures| captures.get(1).map(|m| m.as_str()))
         .ok_or(Error::InternalError(
             "Could not get path from http request".to_string(),
         ))?;
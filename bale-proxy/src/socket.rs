@@ -1,130 +1,718 @@
-use async_std::channel::{Receiver, Sender};
-use async_std::net::{TcpListener, TcpStream};
-use async_std::sync::Arc;
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+use async_std::sync::{Arc, Mutex};
 use async_std::task;
+use futures::future::{self, Future};
 use futures::stream::StreamExt;
 use futures::{AsyncReadExt, AsyncWriteExt};
-use std::net::SocketAddrV4;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 use tokio::select;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::error::Error;
+use crate::frame::{Frame, StreamStatus};
+use crate::http_proxy;
+use crate::socks5;
+use crate::udp;
 use crate::utils::dump_hex;
 use crate::OperationMode;
 
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// How long to wait for a connection to a target or the upstream.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts to connect to the upstream before giving up on a stream.
+const UPSTREAM_CONNECT_ATTEMPTS: u32 = 3;
+/// Delay before trying the upstream again, longer after every attempt.
+const UPSTREAM_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Streams are identified by the Bale user on the other side and the stream id that user picked.
+type StreamKey = (u32, u32);
+
+/// Per-stream routing table, from stream key to the task owning its TCP connection.
+pub(crate) type Routes = Arc<Mutex<HashMap<StreamKey, Sender<Frame>>>>;
+
+/// Local listener of the client, and how the streams it accepts are opened.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Listener {
+    /// Forwards every connection to the server's upstream, as a shadowsocks plugin.
+    Forward(SocketAddr),
+    /// SOCKS5 proxy, the server dials the targets clients ask for.
+    Socks5(SocketAddr),
+    /// HTTP proxy, for `CONNECT` and plain requests with an absolute URI.
+    Http(SocketAddr),
+    /// Forwards every UDP packet to the server's upstream, as a shadowsocks plugin.
+    Udp(SocketAddr),
+}
+
 pub(crate) struct Socket {
-    inbound_tx: Arc<Sender<(u32, Vec<u8>)>>,
-    outbound_rx: Arc<Receiver<(u32, Vec<u8>)>>,
+    inbound_tx: Sender<(u32, Frame)>,
+    outbound_rx: Receiver<(u32, Frame)>,
+    routes: Routes,
+    next_stream_id: AtomicU32,
 }
 
 impl Socket {
     pub(crate) fn new(
-        inbound_tx: Sender<(u32, Vec<u8>)>,
-        outbound_rx: Receiver<(u32, Vec<u8>)>,
+        inbound_tx: Sender<(u32, Frame)>,
+        outbound_rx: Receiver<(u32, Frame)>,
     ) -> Socket {
         Socket {
-            inbound_tx: Arc::new(inbound_tx),
-            outbound_rx: Arc::new(outbound_rx),
+            inbound_tx,
+            outbound_rx,
+            routes: Arc::new(Mutex::new(HashMap::new())),
+            next_stream_id: AtomicU32::new(1),
         }
     }
 
+    /// Runs the server side, connecting streams to the first of the `upstream`
+    /// addresses that answers, or the client side, accepting connections on
+    /// `listeners`.
+    ///
+    /// The server only dials the targets clients ask for with `open_targets`,
+    /// otherwise they can only reach the upstream.
     pub(crate) async fn connect(
         self,
         mode: OperationMode,
-        local_addrs: SocketAddrV4,
-        remote_addrs: SocketAddrV4,
+        upstream: Vec<SocketAddr>,
+        open_targets: bool,
+        listeners: Vec<Listener>,
     ) -> Result<(), Error> {
-        let inbound_tx = self.inbound_tx;
-        let outbound_rx = self.outbound_rx;
         match mode {
-            OperationMode::Server => {
-                Self::bind_server(inbound_tx, outbound_rx, local_addrs, remote_addrs).await
-            }
-            OperationMode::Client(_) => {
-                Self::bind_local(inbound_tx, outbound_rx, local_addrs, remote_addrs).await
-            }
+            OperationMode::Server => self.bind_server(upstream, open_targets).await,
+            OperationMode::Client(server) => self.bind_local(server, listeners).await,
         }
     }
 
-    async fn bind_server(
-        inbound_tx: Arc<Sender<(u32, Vec<u8>)>>,
-        outbound_rx: Arc<Receiver<(u32, Vec<u8>)>>,
-        local_addrs: SocketAddrV4,
-        remote_addrs: SocketAddrV4,
-    ) -> Result<(), Error> {
-        info!("connecting to local : {}", local_addrs);
-        let local = TcpStream::connect(local_addrs).await?;
-        info!("connecting to remote : {}", remote_addrs);
-        let remote = TcpStream::connect(remote_addrs).await?;
-        handle_connection(inbound_tx.clone(), outbound_rx.clone(), local, remote).await;
+    /// Opens a new connection for every stream a client opens, either to
+    /// `upstream` or, with `open_targets`, to the target of the stream.
+    async fn bind_server(self, upstream: Vec<SocketAddr>, open_targets: bool) -> Result<(), Error> {
+        while let Ok((peer, frame)) = self.outbound_rx.recv().await {
+            let (stream_id, target) = match frame {
+                Frame::Open { stream_id } => {
+                    if upstream.is_empty() {
+                        warn!(
+                            "user #{} opened stream #{} but there is no upstream",
+                            peer, stream_id
+                        );
+                        self.send_status(peer, stream_id, StreamStatus::Unreachable)
+                            .await;
+                        continue;
+                    }
+                    (stream_id, None)
+                }
+                Frame::Connect { stream_id, target } => {
+                    if !open_targets {
+                        warn!(
+                            "user #{} asked stream #{} to connect to {} but open_targets is off",
+                            peer, stream_id, target
+                        );
+                        self.send_status(peer, stream_id, StreamStatus::Refused)
+                            .await;
+                        continue;
+                    }
+                    (stream_id, Some(target))
+                }
+                Frame::Datagram { flow_id, .. } => {
+                    let route = self.routes.lock().await.get(&(peer, flow_id)).cloned();
+                    let frame = match route {
+                        Some(tx) => match tx.send(frame).await {
+                            Ok(()) => continue,
+                            // The flow went idle, start it again.
+                            Err(err) => err.into_inner(),
+                        },
+                        None => frame,
+                    };
+                    let tx = self
+                        .spawn_flow(peer, flow_id, upstream.clone(), open_targets)
+                        .await;
+                    let _ = tx.send(frame).await;
+                    continue;
+                }
+                frame => {
+                    self.dispatch(peer, frame).await;
+                    continue;
+                }
+            };
+
+            // A client that restarted reuses its stream ids.
+            if let Some(previous) = self.routes.lock().await.remove(&(peer, stream_id)) {
+                warn!(
+                    "user #{} reopened stream #{}, closing the previous connection",
+                    peer, stream_id
+                );
+                previous.close();
+            }
+
+            // Connect in the background, frames that arrive meanwhile wait in
+            // the stream's channel.
+            let upstream = upstream.clone();
+            let inbound_tx = self.inbound_tx.clone();
+            let connect = async move {
+                let res = connect_stream(target.as_deref(), &upstream).await;
+                let status = match &res {
+                    Ok(_) => StreamStatus::Connected,
+                    Err(err) => StreamStatus::from(err),
+                };
+                let _ = inbound_tx
+                    .send((peer, Frame::Status { stream_id, status }))
+                    .await;
+                res
+            };
+            self.spawn_connection(peer, stream_id, connect, None).await;
+        }
         Ok(())
     }
 
-    async fn bind_local(
-        inbound_tx: Arc<Sender<(u32, Vec<u8>)>>,
-        outbound_rx: Arc<Receiver<(u32, Vec<u8>)>>,
-        local_addrs: SocketAddrV4,
-        remote_addrs: SocketAddrV4,
-    ) -> Result<(), Error> {
-        // Open up a TCP connection and create a URL.
-        let listener = TcpListener::bind(local_addrs).await?;
-        info!("listening on local : {}", listener.local_addr()?);
+    /// Accepts connections on every listener and opens a new stream for each of them.
+    async fn bind_local(self, server: u32, listeners: Vec<Listener>) -> Result<(), Error> {
+        let socket = Arc::new(self);
+
+        let router = socket.clone();
+        task::spawn(async move {
+            while let Ok((peer, frame)) = router.outbound_rx.recv().await {
+                router.dispatch(peer, frame).await;
+            }
+        });
+
+        // Hosts like localhost resolve to several addresses, listening on some
+        // of them is enough.
+        let count = listeners.len();
+        let results = future::join_all(listeners.into_iter().map(|listener| {
+            let socket = socket.clone();
+            async move {
+                let res = socket.listen(server, listener).await;
+                if let Err(err) = &res {
+                    error!("could not listen on {:?} : {}", listener, err);
+                }
+                res
+            }
+        }))
+        .await;
+
+        match results.into_iter().find(Result::is_ok) {
+            Some(res) => res,
+            None if count > 0 => Err(Error::InternalError(
+                "could not listen on any address".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    async fn listen(&self, server: u32, listener: Listener) -> Result<(), Error> {
+        let local_addrs = match listener {
+            Listener::Forward(local_addrs)
+            | Listener::Socks5(local_addrs)
+            | Listener::Http(local_addrs) => local_addrs,
+            Listener::Udp(local_addrs) => {
+                let socket = UdpSocket::bind(local_addrs).await?;
+                info!("listening on local : {} (UDP)", socket.local_addr()?);
+                udp::serve(
+                    socket,
+                    server,
+                    false,
+                    self.inbound_tx.clone(),
+                    self.routes.clone(),
+                    &self.next_stream_id,
+                    future::pending(),
+                )
+                .await;
+                return Ok(());
+            }
+        };
+        let tcp_listener = TcpListener::bind(local_addrs).await?;
+        info!(
+            "listening on local : {} ({:?})",
+            tcp_listener.local_addr()?,
+            listener
+        );
 
-        listener
+        tcp_listener
             .incoming()
             .for_each_concurrent(/* limit */ None, |stream| async {
-                let stream = stream.unwrap();
-
-                let (i_tx, o_rx) = (inbound_tx.clone(), outbound_rx.clone());
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("could not accept connection : {:?}", err);
+                        return;
+                    }
+                };
 
-                task::spawn(async move {
-                    info!("connecting to remote : {}", remote_addrs);
-                    let remote = TcpStream::connect(remote_addrs).await.unwrap();
-                    handle_connection(i_tx, o_rx, remote, stream).await;
-                });
+                let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+                let mut frames = Vec::new();
+                let pending = match listener {
+                    Listener::Forward(_) => {
+                        frames.push(Frame::Open { stream_id });
+                        None
+                    }
+                    Listener::Socks5(_) => match socks5::accept(&mut stream).await {
+                        Ok(socks5::Request::Connect(target)) => {
+                            debug!("stream #{} connects to {}", stream_id, target);
+                            frames.push(Frame::Connect { stream_id, target });
+                            Some(Pending::Socks5)
+                        }
+                        Ok(socks5::Request::UdpAssociate) => {
+                            if let Err(err) = self.associate(server, stream).await {
+                                warn!("could not relay UDP for SOCKS5 client : {}", err);
+                            }
+                            return;
+                        }
+                        Err(err) => {
+                            warn!("could not accept SOCKS5 connection : {}", err);
+                            return;
+                        }
+                    },
+                    Listener::Udp(_) => unreachable!(),
+                    Listener::Http(_) => match http_proxy::accept(&mut stream).await {
+                        Ok(http_proxy::Request::Connect(target, early)) => {
+                            debug!("stream #{} connects to {}", stream_id, target);
+                            frames.push(Frame::Connect { stream_id, target });
+                            if !early.is_empty() {
+                                frames.push(Frame::Data {
+                                    stream_id,
+                                    payload: early,
+                                });
+                            }
+                            Some(Pending::HttpConnect)
+                        }
+                        Ok(http_proxy::Request::Forward(target, initial)) => {
+                            debug!("stream #{} connects to {}", stream_id, target);
+                            frames.push(Frame::Connect { stream_id, target });
+                            frames.push(Frame::Data {
+                                stream_id,
+                                payload: initial,
+                            });
+                            Some(Pending::Http)
+                        }
+                        Err(err) => {
+                            warn!("could not accept HTTP proxy connection : {}", err);
+                            return;
+                        }
+                    },
+                };
+                for frame in frames {
+                    if let Err(err) = self.inbound_tx.send((server, frame)).await {
+                        error!("could not send open frame to inbound_tx : {:?}", err);
+                        return;
+                    }
+                }
+                self.spawn_connection(server, stream_id, future::ok(stream), pending)
+                    .await;
             })
             .await;
         Ok(())
     }
+
+    /// Relays UDP packets of a SOCKS5 client for as long as it keeps the
+    /// connection of its request open.
+    async fn associate(&self, server: u32, mut stream: TcpStream) -> Result<(), Error> {
+        let socket = UdpSocket::bind((stream.local_addr()?.ip(), 0)).await?;
+        socks5::reply_bound(&mut stream, socket.local_addr()?).await?;
+
+        let closed = async move {
+            let mut buffer = [0; 64];
+            while let Ok(size) = stream.read(&mut buffer).await {
+                if size == 0 {
+                    break;
+                }
+            }
+        };
+        udp::serve(
+            socket,
+            server,
+            true,
+            self.inbound_tx.clone(),
+            self.routes.clone(),
+            &self.next_stream_id,
+            closed,
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn send_status(&self, peer: u32, stream_id: u32, status: StreamStatus) {
+        let _ = self
+            .inbound_tx
+            .send((peer, Frame::Status { stream_id, status }))
+            .await;
+    }
+
+    /// Hands a frame received from the tunnel to the connection owning its stream.
+    async fn dispatch(&self, peer: u32, frame: Frame) {
+        let stream_id = frame.stream_id();
+        let route = self.routes.lock().await.get(&(peer, stream_id)).cloned();
+        match route {
+            Some(tx) => {
+                if let Err(err) = tx.send(frame).await {
+                    debug!("stream #{} is already gone : {:?}", stream_id, err);
+                }
+            }
+            None => match frame {
+                Frame::Fin { .. } | Frame::Reset { .. } => {}
+                Frame::Datagram { .. } => debug!("dropping datagram of idle flow #{}", stream_id),
+                _ => {
+                    warn!(
+                        "received frame for unknown stream #{} of user #{}",
+                        stream_id, peer
+                    );
+                    let _ = self
+                        .inbound_tx
+                        .send((peer, Frame::Reset { stream_id }))
+                        .await;
+                }
+            },
+        }
+    }
+
+    /// Routes the flow to a new task that relays its packets, and returns the
+    /// route.
+    async fn spawn_flow(
+        &self,
+        peer: u32,
+        flow_id: u32,
+        upstream: Vec<SocketAddr>,
+        open_targets: bool,
+    ) -> Sender<Frame> {
+        let (tx, rx) = unbounded();
+        self.routes.lock().await.insert((peer, flow_id), tx.clone());
+
+        let inbound_tx = self.inbound_tx.clone();
+        let routes = self.routes.clone();
+        task::spawn(async move {
+            udp::relay_flow(peer, flow_id, upstream, open_targets, inbound_tx, rx).await;
+            remove_route(&routes, (peer, flow_id)).await;
+        });
+        tx
+    }
+
+    /// Routes the stream to a new task that runs its connection once `connect`
+    /// returns it.
+    async fn spawn_connection<F>(
+        &self,
+        peer: u32,
+        stream_id: u32,
+        connect: F,
+        pending: Option<Pending>,
+    ) where
+        F: Future<Output = io::Result<TcpStream>> + Send + 'static,
+    {
+        let (tx, rx) = unbounded();
+        self.routes.lock().await.insert((peer, stream_id), tx);
+
+        let inbound_tx = self.inbound_tx.clone();
+        let routes = self.routes.clone();
+        task::spawn(async move {
+            match connect.await {
+                Ok(stream) => {
+                    handle_connection(peer, stream_id, stream, inbound_tx, rx, pending).await
+                }
+                Err(err) => error!("could not connect stream #{} : {:?}", stream_id, err),
+            }
+
+            remove_route(&routes, (peer, stream_id)).await;
+            debug!("stream #{} of user #{} closed", stream_id, peer);
+        });
+    }
 }
 
+/// Removes the route of a task that ended and dropped its receiver. A stream
+/// that was reopened meanwhile keeps the route of its new connection, which
+/// is still open.
+async fn remove_route(routes: &Routes, key: StreamKey) {
+    let mut routes = routes.lock().await;
+    if routes.get(&key).is_some_and(|tx| tx.is_closed()) {
+        routes.remove(&key);
+    }
+}
+
+/// Connects to `target`, or to the upstream. The upstream is tried a few
+/// times, since it may be restarting.
+async fn connect_stream(target: Option<&str>, upstream: &[SocketAddr]) -> io::Result<TcpStream> {
+    if let Some(target) = target {
+        info!("connecting to {}", target);
+        return async_std::io::timeout(CONNECT_TIMEOUT, TcpStream::connect(target)).await;
+    }
+
+    let mut attempt = 1;
+    loop {
+        info!("connecting to upstream : {:?}", upstream);
+        match async_std::io::timeout(CONNECT_TIMEOUT, TcpStream::connect(upstream)).await {
+            Err(err) if attempt < UPSTREAM_CONNECT_ATTEMPTS => {
+                warn!("could not connect to upstream, trying again : {:?}", err);
+                task::sleep(UPSTREAM_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Local client waiting to hear whether the server could open its stream.
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Socks5,
+    HttpConnect,
+    Http,
+}
+
+impl Pending {
+    async fn answer(self, stream: &mut TcpStream, status: StreamStatus) -> Result<(), Error> {
+        match self {
+            Pending::Socks5 => socks5::reply_status(stream, status).await,
+            Pending::HttpConnect => http_proxy::reply_status(stream, true, status).await,
+            Pending::Http => http_proxy::reply_status(stream, false, status).await,
+        }
+    }
+}
+
+/// Waits for the status of a stream the server is connecting.
+async fn wait_for_status(stream_id: u32, outbound_rx: &Receiver<Frame>) -> StreamStatus {
+    loop {
+        match outbound_rx.recv().await {
+            Ok(Frame::Status { status, .. }) => return status,
+            Ok(Frame::Reset { .. }) | Err(_) => return StreamStatus::Unreachable,
+            Ok(frame) => warn!(
+                "dropping {:?} received before the status of stream #{}",
+                frame, stream_id
+            ),
+        }
+    }
+}
+
+/// Copies data between `stream` and the tunnel until both directions are done.
+///
+/// EOF on one side is sent as a FIN and half-closes the socket on the other
+/// side, so protocols that wait for the end of the request still work. Errors
+/// are sent as a RST and close both sockets.
+///
+/// A `pending` client is answered once the status of the stream arrives, and
+/// nothing is read from it before.
 async fn handle_connection(
-    inbound_tx: Arc<Sender<(u32, Vec<u8>)>>,
-    outbound_rx: Arc<Receiver<(u32, Vec<u8>)>>,
-    mut local: TcpStream,
-    mut remote: TcpStream,
+    peer: u32,
+    stream_id: u32,
+    mut stream: TcpStream,
+    inbound_tx: Sender<(u32, Frame)>,
+    outbound_rx: Receiver<Frame>,
+    pending: Option<Pending>,
 ) {
-    let mut buffer = [0; 1024];
-    loop {
+    if let Some(pending) = pending {
+        let status = wait_for_status(stream_id, &outbound_rx).await;
+        if let Err(err) = pending.answer(&mut stream, status).await {
+            debug!("could not answer stream #{} : {}", stream_id, err);
+        }
+        if status != StreamStatus::Connected {
+            debug!("server could not open stream #{} : {:?}", stream_id, status);
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut reading = true;
+    let mut writing = true;
+    while reading || writing {
         select! {
-            res = remote.read(&mut buffer) => {
+            res = stream.read(&mut buffer), if reading => {
                 match res {
+                    Ok(0) => {
+                        debug!("stream #{} of user #{} reached EOF", stream_id, peer);
+                        reading = false;
+                        if inbound_tx.send((peer, Frame::Fin { stream_id })).await.is_err() {
+                            break;
+                        }
+                    },
                     Ok(size) => {
-                        if size > 0 {
-                            debug!(
-                                "{} -> {} :{}",
-                                local.local_addr().unwrap(),
-                                remote.local_addr().unwrap(),
-                                dump_hex(&buffer[..size]),
-                            );
-
-                            if let Err(err) = inbound_tx.send((0, (&buffer[..size]).to_vec())).await {
-                                error!("could not send buffer to inbound_tx : {:?}", err);
-                            }
+                        debug!("stream #{} -> user #{} :{}", stream_id, peer, dump_hex(&buffer[..size]));
+
+                        let frame = Frame::Data { stream_id, payload: buffer[..size].to_vec() };
+                        if let Err(err) = inbound_tx.send((peer, frame)).await {
+                            error!("could not send buffer to inbound_tx : {:?}", err);
+                            break;
                         }
                     },
-                    Err(err) => error!("error reading from remote socket : {:?}", err),
+                    Err(err) => {
+                        error!("error reading from stream #{} : {:?}", stream_id, err);
+                        let _ = inbound_tx.send((peer, Frame::Reset { stream_id })).await;
+                        break;
+                    },
                 }
             }
             res = outbound_rx.recv() => {
                 match res {
-                    Ok((_, response)) => {
-                        local.write(response.as_slice()).await.unwrap();
-                        local.flush().await.unwrap();
+                    Ok(Frame::Data { payload, .. }) if writing => {
+                        if let Err(err) = stream.write_all(payload.as_slice()).await {
+                            error!("error writing to stream #{} : {:?}", stream_id, err);
+                            let _ = inbound_tx.send((peer, Frame::Reset { stream_id })).await;
+                            break;
+                        }
                     },
-                    Err(err) => error!("error reading from outbound socket : {:?}", err),
+                    Ok(Frame::Data { .. }) => warn!("dropping data received after FIN on stream #{}", stream_id),
+                    Ok(Frame::Fin { .. }) => {
+                        writing = false;
+                        let _ = stream.shutdown(Shutdown::Write);
+                    },
+                    Ok(Frame::Status { status, .. }) => {
+                        if status != StreamStatus::Connected {
+                            debug!("server could not open stream #{} : {:?}", stream_id, status);
+                            break;
+                        }
+                    },
+                    Ok(Frame::Reset { .. }) | Err(_) => break,
+                    Ok(Frame::Datagram { .. }) => {}
+                    Ok(Frame::Open { .. }) | Ok(Frame::Connect { .. }) => warn!("stream #{} is already open", stream_id),
                 }
             }
         }
     }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: u32 = 1;
+
+    /// Server side of the sockets, fed and read like the tunnel does.
+    struct Server {
+        outbound_tx: Sender<(u32, Frame)>,
+        inbound_rx: Receiver<(u32, Frame)>,
+        routes: Routes,
+    }
+
+    fn server(upstream: SocketAddr, open_targets: bool) -> Server {
+        let (inbound_tx, inbound_rx) = unbounded();
+        let (outbound_tx, outbound_rx) = unbounded();
+        let socket = Socket::new(inbound_tx, outbound_rx);
+        let routes = socket.routes.clone();
+        task::spawn(socket.bind_server(vec![upstream], open_targets));
+        Server {
+            outbound_tx,
+            inbound_rx,
+            routes,
+        }
+    }
+
+    async fn wait_for_no_routes(routes: &Routes) {
+        for _ in 0..100 {
+            if routes.lock().await.is_empty() {
+                return;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("routes were not removed");
+    }
+
+    #[tokio::test]
+    async fn removes_routes_of_finished_streams() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let Server {
+            outbound_tx,
+            inbound_rx,
+            routes,
+        } = server(upstream.local_addr().unwrap(), false);
+        task::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            stream.shutdown(Shutdown::Both).unwrap();
+        });
+
+        let stream_id = 7;
+        outbound_tx
+            .send((PEER, Frame::Open { stream_id }))
+            .await
+            .unwrap();
+        loop {
+            match inbound_rx.recv().await.unwrap() {
+                (PEER, Frame::Fin { .. }) => break,
+                (PEER, Frame::Status { .. }) => {}
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+        outbound_tx
+            .send((PEER, Frame::Fin { stream_id }))
+            .await
+            .unwrap();
+        wait_for_no_routes(&routes).await;
+    }
+
+    #[tokio::test]
+    async fn restarts_idle_flows() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = server(upstream.local_addr().unwrap(), false);
+        let (outbound_tx, routes) = (&server.outbound_tx, &server.routes);
+        let datagram = |payload: &[u8]| Frame::Datagram {
+            flow_id: 3,
+            target: String::new(),
+            payload: payload.to_vec(),
+        };
+
+        let mut buffer = [0; 16];
+        for payload in [b"a", b"b"].iter() {
+            outbound_tx.send((PEER, datagram(*payload))).await.unwrap();
+            let (size, _) =
+                async_std::io::timeout(Duration::from_secs(5), upstream.recv_from(&mut buffer))
+                    .await
+                    .unwrap();
+            assert_eq!(&buffer[..size], *payload);
+            wait_for_no_routes(routes).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_targets_unless_open() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let connect = |stream_id| Frame::Connect {
+            stream_id,
+            target: target.clone(),
+        };
+
+        let closed = server(target.parse().unwrap(), false);
+        closed.outbound_tx.send((PEER, connect(1))).await.unwrap();
+        match closed.inbound_rx.recv().await.unwrap() {
+            (PEER, Frame::Status { status, .. }) => assert_eq!(status, StreamStatus::Refused),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        let open = server(target.parse().unwrap(), true);
+        open.outbound_tx.send((PEER, connect(1))).await.unwrap();
+        match open.inbound_rx.recv().await.unwrap() {
+            (PEER, Frame::Status { status, .. }) => assert_eq!(status, StreamStatus::Connected),
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_datagrams_to_targets_unless_open() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let datagram = Frame::Datagram {
+            flow_id: 3,
+            target: target.local_addr().unwrap().to_string(),
+            payload: b"a".to_vec(),
+        };
+        let mut buffer = [0; 16];
+
+        let closed = server(upstream.local_addr().unwrap(), false);
+        closed
+            .outbound_tx
+            .send((PEER, datagram.clone()))
+            .await
+            .unwrap();
+        let res =
+            async_std::io::timeout(Duration::from_millis(100), target.recv_from(&mut buffer)).await;
+        assert!(res.is_err());
+
+        let open = server(upstream.local_addr().unwrap(), true);
+        open.outbound_tx.send((PEER, datagram)).await.unwrap();
+        let (size, _) =
+            async_std::io::timeout(Duration::from_secs(5), target.recv_from(&mut buffer))
+                .await
+                .unwrap();
+        assert_eq!(&buffer[..size], b"a");
+    }
 }
@@ -0,0 +1,300 @@
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::error::Error;
+use crate::frame::StreamStatus;
+
+const VERSION: u8 = 5;
+
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+const COMMAND_CONNECT: u8 = 1;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// What a SOCKS5 client asked for.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Request {
+    /// Connect to `host:port`, to be answered with [`reply_status`].
+    Connect(String),
+    /// Relay UDP packets, to be answered with [`reply_bound`].
+    UdpAssociate,
+}
+
+/// Reads the SOCKS5 greeting and request of a new client.
+///
+/// Only CONNECT and UDP ASSOCIATE without authentication are supported
+/// (RFC 1928). Requests that are not supported are answered here, the others
+/// once the server on the other side of the tunnel tried them.
+pub(crate) async fn accept<S>(stream: &mut S) -> Result<Request, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, methods] = read_array(stream).await?;
+    check_version(version)?;
+    let mut methods = vec![0; methods as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        return Err(Error::InvalidFrame(
+            "SOCKS5 client requires authentication".to_string(),
+        ));
+    }
+    stream
+        .write_all(&[VERSION, METHOD_NO_AUTHENTICATION])
+        .await?;
+
+    let [version, command, _reserved] = read_array(stream).await?;
+    check_version(version)?;
+    let target = match read_address(stream).await? {
+        Some(target) => target,
+        None => {
+            reply(stream, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(Error::InvalidFrame(
+                "unsupported SOCKS5 address type".to_string(),
+            ));
+        }
+    };
+
+    match command {
+        COMMAND_CONNECT => Ok(Request::Connect(target)),
+        // The address is where the client will send from, if it knows it,
+        // but packets are accepted from anywhere anyway.
+        COMMAND_UDP_ASSOCIATE => Ok(Request::UdpAssociate),
+        _ => {
+            reply(stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+            Err(Error::InvalidFrame(format!(
+                "unsupported SOCKS5 command {}",
+                command
+            )))
+        }
+    }
+}
+
+/// Answers a CONNECT request with whether the server could connect.
+pub(crate) async fn reply_status<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: StreamStatus,
+) -> Result<(), Error> {
+    let reply_code = match status {
+        StreamStatus::Connected => REPLY_SUCCEEDED,
+        StreamStatus::Refused => REPLY_CONNECTION_REFUSED,
+        StreamStatus::Unreachable | StreamStatus::TimedOut => REPLY_HOST_UNREACHABLE,
+    };
+    reply(stream, reply_code).await
+}
+
+/// Answers a UDP ASSOCIATE request with the address to send packets to.
+pub(crate) async fn reply_bound<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    bound: SocketAddr,
+) -> Result<(), Error> {
+    let mut message = vec![VERSION, REPLY_SUCCEEDED, 0];
+    write_address(&mut message, &bound.to_string());
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+/// Splits a packet sent to the UDP relay into its target and payload.
+pub(crate) fn parse_datagram(packet: &[u8]) -> Result<(String, &[u8]), Error> {
+    let mut rest = packet;
+    let header = take(&mut rest, 4)?;
+    if header[2] != 0 {
+        return Err(Error::InvalidFrame(
+            "fragmented SOCKS5 datagrams are not supported".to_string(),
+        ));
+    }
+    let host = match header[3] {
+        ADDRESS_IPV4 => {
+            let octets: [u8; 4] = take(&mut rest, 4)?.try_into().unwrap();
+            Ipv4Addr::from(octets).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let octets: [u8; 16] = take(&mut rest, 16)?.try_into().unwrap();
+            format!("[{}]", Ipv6Addr::from(octets))
+        }
+        ADDRESS_DOMAIN => {
+            let length = take(&mut rest, 1)?[0] as usize;
+            String::from_utf8(take(&mut rest, length)?.to_vec())?
+        }
+        _ => {
+            return Err(Error::InvalidFrame(
+                "unsupported SOCKS5 address type".to_string(),
+            ))
+        }
+    };
+    let port = u16::from_be_bytes(take(&mut rest, 2)?.try_into().unwrap());
+    Ok((format!("{}:{}", host, port), rest))
+}
+
+/// Puts the header of the UDP relay in front of a packet from `source`.
+pub(crate) fn datagram(source: &str, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0];
+    write_address(&mut packet, source);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Reads an address and port as `host:port`, or nothing if the type of the
+/// address is unknown.
+async fn read_address<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<String>, Error> {
+    let [address_type] = read_array(stream).await?;
+    let host = match address_type {
+        ADDRESS_IPV4 => Ipv4Addr::from(read_array::<_, 4>(stream).await?).to_string(),
+        ADDRESS_IPV6 => format!("[{}]", Ipv6Addr::from(read_array::<_, 16>(stream).await?)),
+        ADDRESS_DOMAIN => {
+            let [length] = read_array(stream).await?;
+            let mut domain = vec![0; length as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain)?
+        }
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes(read_array(stream).await?);
+    Ok(Some(format!("{}:{}", host, port)))
+}
+
+fn write_address(buf: &mut Vec<u8>, address: &str) {
+    match address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(address)) => {
+            buf.push(ADDRESS_IPV4);
+            buf.extend_from_slice(&address.ip().octets());
+            buf.extend_from_slice(&address.port().to_be_bytes());
+        }
+        Ok(SocketAddr::V6(address)) => {
+            buf.push(ADDRESS_IPV6);
+            buf.extend_from_slice(&address.ip().octets());
+            buf.extend_from_slice(&address.port().to_be_bytes());
+        }
+        Err(_) => {
+            let (host, port) = address.rsplit_once(':').unwrap_or((address, "0"));
+            buf.push(ADDRESS_DOMAIN);
+            buf.push(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+            buf.extend_from_slice(&port.parse::<u16>().unwrap_or(0).to_be_bytes());
+        }
+    }
+}
+
+fn check_version(version: u8) -> Result<(), Error> {
+    if version != VERSION {
+        return Err(Error::InvalidFrame(format!(
+            "unsupported SOCKS version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+/// Answers the request, with an unspecified bound address since the real
+/// connection is made by the server.
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: u8) -> Result<(), Error> {
+    stream
+        .write_all(&[VERSION, reply, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// Takes the next `size` bytes of a datagram.
+fn take<'a>(buf: &mut &'a [u8], size: usize) -> Result<&'a [u8], Error> {
+    if buf.len() < size {
+        return Err(Error::InvalidFrame(format!(
+            "SOCKS5 datagram is truncated, expected {} more bytes but got {}",
+            size,
+            buf.len()
+        )));
+    }
+    let (head, tail) = buf.split_at(size);
+    *buf = tail;
+    Ok(head)
+}
+
+async fn read_array<S: AsyncRead + Unpin, const N: usize>(
+    stream: &mut S,
+) -> Result<[u8; N], Error> {
+    let mut buf = vec![0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+
+    async fn request(request: &[u8]) -> (Result<Request, Error>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.write_all(request).await.unwrap();
+        let target = accept(&mut server).await;
+        drop(server);
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        (target, replies)
+    }
+
+    #[async_std::test]
+    async fn accepts_connect_requests() {
+        let mut domain = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&[1, 187]);
+        let (target, replies) = request(&domain).await;
+        assert_eq!(
+            target.unwrap(),
+            Request::Connect("example.com:443".to_string())
+        );
+        assert_eq!(replies, vec![5, 0]);
+
+        let mut ipv6 = vec![5, 1, 0, 5, 1, 0, 4];
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&[0, 80]);
+        assert_eq!(
+            request(&ipv6).await.0.unwrap(),
+            Request::Connect("[::1]:80".to_string())
+        );
+    }
+
+    #[test]
+    fn wraps_datagrams() {
+        for source in &["1.2.3.4:53", "[::1]:5353", "example.com:80"] {
+            let packet = datagram(source, b"payload");
+            let (target, payload) = parse_datagram(&packet).unwrap();
+            assert_eq!((target.as_str(), payload), (*source, &b"payload"[..]));
+        }
+        assert!(parse_datagram(&[0, 0, 1, 1, 1, 2, 3, 4, 0, 53]).is_err());
+
+        let packet = datagram("example.com:80", b"");
+        for size in 0..packet.len() {
+            assert!(parse_datagram(&packet[..size]).is_err());
+        }
+        assert!(parse_datagram(&datagram("[::1]:53", b"")[..10]).is_err());
+    }
+
+    #[async_std::test]
+    async fn rejects_other_commands() {
+        // BIND to 127.0.0.1:80
+        let (target, replies) = request(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await;
+        assert!(target.is_err());
+        assert_eq!(replies[2..4], [5, REPLY_COMMAND_NOT_SUPPORTED]);
+
+        let (target, replies) = request(&[5, 1, 2]).await;
+        assert!(target.is_err());
+        assert_eq!(replies, vec![5, METHOD_NOT_ACCEPTABLE]);
+    }
+}
@@ -0,0 +1,247 @@
+use async_std::channel::Sender;
+use async_trait::async_trait;
+use bale::BaleClient;
+use std::fmt::{self, Display, Formatter};
+use tokio::sync::watch;
+
+use crate::error::Error;
+
+/// Chat messages are sent to and received in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Chat {
+    /// Private chat with a user.
+    User(u32),
+    /// Group, read by all of its members.
+    Group(u32),
+}
+
+impl Display for Chat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Chat::User(id) => write!(f, "user #{}", id),
+            Chat::Group(id) => write!(f, "group #{}", id),
+        }
+    }
+}
+
+/// Message received from another user, with the chat it was sent in and
+/// what identifies it there.
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+    pub(crate) chat: Chat,
+    pub(crate) sender_id: u32,
+    pub(crate) id: MessageId,
+    pub(crate) message: Message,
+}
+
+/// Identifies a message in a chat, to delete it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MessageId {
+    pub(crate) rid: u64,
+    pub(crate) date: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Message {
+    Text(String),
+    Document(Document),
+}
+
+/// File attached to a document message.
+#[derive(Debug, Clone)]
+pub(crate) struct Document {
+    pub(crate) file_id: i64,
+    pub(crate) access_hash: i64,
+    pub(crate) size: i32,
+    pub(crate) name: String,
+    pub(crate) mime_type: String,
+}
+
+/// Counters of the send queue, to see whether the messenger keeps up.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutboxStats {
+    /// Messages waiting to be sent.
+    pub(crate) queued: usize,
+    /// Messages sent since the start, after batching.
+    pub(crate) sent: u64,
+    /// Number of times the messenger asked to slow down.
+    pub(crate) rate_limited: u64,
+}
+
+impl Display for OutboxStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} queued, {} sent, rate limited {} times",
+            self.queued, self.sent, self.rate_limited
+        )
+    }
+}
+
+/// Messenger carrying the tunnel between accounts, Bale being the first one.
+///
+/// Messages are queued and sent by [`MessengerTransport::run_outbox`] at the
+/// pace the messenger allows. Chats the messenger doesn't know are reported
+/// as [`Error::UnknownChat`].
+#[async_trait]
+pub(crate) trait MessengerTransport: Send + Sync {
+    /// Forwards the messages sent to this account and to its groups to `tx`
+    /// until `tx` is closed.
+    async fn subscribe_to_updates(&self, tx: Sender<Received>) -> Result<(), Error>;
+
+    /// Sends the queued messages until `failed_tx` is closed. The ones that
+    /// could not be sent are reported to `failed_tx` with their chat.
+    async fn run_outbox(&self, failed_tx: Sender<(Chat, Error)>) -> Result<(), Error>;
+
+    fn queue_message(&self, chat: Chat, text: String);
+
+    fn queue_document(&self, chat: Chat, name: String, content: Vec<u8>);
+
+    /// Deletes messages of a chat, for all of its members.
+    fn queue_delete(&self, chat: Chat, ids: Vec<MessageId>);
+
+    /// Size of the send queue, to stop reading from sockets while the
+    /// messenger can't keep up.
+    fn outbox_stats(&self) -> watch::Receiver<OutboxStats>;
+
+    async fn download_file(&self, document: &Document) -> Result<Vec<u8>, Error>;
+}
+
+impl From<bale::Peer> for Chat {
+    fn from(peer: bale::Peer) -> Chat {
+        match peer {
+            bale::Peer::User(id) => Chat::User(id),
+            bale::Peer::Group(id) => Chat::Group(id),
+        }
+    }
+}
+
+impl From<Chat> for bale::Peer {
+    fn from(chat: Chat) -> bale::Peer {
+        match chat {
+            Chat::User(id) => bale::Peer::User(id),
+            Chat::Group(id) => bale::Peer::Group(id),
+        }
+    }
+}
+
+impl From<bale::Received> for Received {
+    fn from(received: bale::Received) -> Received {
+        Received {
+            chat: received.peer.into(),
+            sender_id: received.sender_id,
+            id: MessageId {
+                rid: received.id.rid,
+                date: received.id.date,
+            },
+            message: match received.message {
+                bale::Message::Text(text) => Message::Text(text),
+                bale::Message::Document(document) => Message::Document(Document {
+                    file_id: document.file_id,
+                    access_hash: document.access_hash,
+                    size: document.size,
+                    name: document.name,
+                    mime_type: document.mime_type,
+                }),
+            },
+        }
+    }
+}
+
+impl From<bale::OutboxStats> for OutboxStats {
+    fn from(stats: bale::OutboxStats) -> OutboxStats {
+        OutboxStats {
+            queued: stats.queued,
+            sent: stats.sent,
+            rate_limited: stats.rate_limited,
+        }
+    }
+}
+
+/// Errors about a chat become [`Error::UnknownChat`], the others are kept
+/// as they are.
+fn transport_error(err: bale::Error) -> Error {
+    match err {
+        bale::Error::UnknownPeer(peer) => Error::UnknownChat(peer.into()),
+        err => Error::BaleError(err),
+    }
+}
+
+#[async_trait]
+impl MessengerTransport for BaleClient {
+    async fn subscribe_to_updates(&self, tx: Sender<Received>) -> Result<(), Error> {
+        let (bale_tx, bale_rx) = async_std::channel::unbounded::<bale::Received>();
+        let forward = async move {
+            while let Ok(received) = bale_rx.recv().await {
+                if tx.send(received.into()).await.is_err() {
+                    break;
+                }
+            }
+        };
+        // Dropping `bale_rx` once `tx` is closed stops the subscription too.
+        let (res, ()) = tokio::join!(BaleClient::subscribe_to_updates(self, bale_tx), forward);
+        res.map_err(transport_error)
+    }
+
+    async fn run_outbox(&self, failed_tx: Sender<(Chat, Error)>) -> Result<(), Error> {
+        let (bale_tx, bale_rx) = async_std::channel::unbounded::<(bale::Peer, bale::Error)>();
+        let forward = async move {
+            while let Ok((peer, err)) = bale_rx.recv().await {
+                if failed_tx
+                    .send((peer.into(), transport_error(err)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        };
+        let (res, ()) = tokio::join!(BaleClient::run_outbox(self, bale_tx), forward);
+        res.map_err(transport_error)
+    }
+
+    fn queue_message(&self, chat: Chat, text: String) {
+        BaleClient::queue_message(self, chat.into(), text)
+    }
+
+    fn queue_document(&self, chat: Chat, name: String, content: Vec<u8>) {
+        BaleClient::queue_document(self, chat.into(), name, content)
+    }
+
+    fn queue_delete(&self, chat: Chat, ids: Vec<MessageId>) {
+        let ids = ids
+            .into_iter()
+            .map(|id| bale::MessageId {
+                rid: id.rid,
+                date: id.date,
+            })
+            .collect();
+        BaleClient::queue_delete(self, chat.into(), ids)
+    }
+
+    fn outbox_stats(&self) -> watch::Receiver<OutboxStats> {
+        let mut bale_stats = BaleClient::outbox_stats(self);
+        let (tx, rx) = watch::channel(OutboxStats::from(*bale_stats.borrow()));
+        tokio::spawn(async move {
+            while bale_stats.changed().await.is_ok() {
+                if tx.send(OutboxStats::from(*bale_stats.borrow())).is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    async fn download_file(&self, document: &Document) -> Result<Vec<u8>, Error> {
+        let document = bale::Document {
+            file_id: document.file_id,
+            access_hash: document.access_hash,
+            size: document.size,
+            name: document.name.clone(),
+            mime_type: document.mime_type.clone(),
+        };
+        BaleClient::download_file(self, &document)
+            .await
+            .map_err(transport_error)
+    }
+}
@@ -0,0 +1,636 @@
+use async_std::channel::Sender;
+use async_std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use tracing::{debug, error, info, trace, warn};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::codec::{Base64, PayloadCodec};
+use crate::crypto::Packet;
+use crate::error::Error;
+use crate::frame::{Frame, StreamStatus};
+use crate::session::{Outgoing, Session};
+use crate::transport::{Chat, Message, MessageId, MessengerTransport, Received};
+use crate::utils::dump_hex;
+
+/// Longest mention of the recipient at the start of messages sent in groups.
+pub(crate) const MAX_MENTION_LENGTH: usize = "@4294967295 ".len();
+
+/// Moves frames between the local sockets and the peers on the other side of the chat.
+///
+/// A server keeps one session per user that talks to it, a client only has a
+/// session with its server. Both sides may talk in a private chat or in a
+/// group shared with other clients and servers. Every member of a group reads
+/// every message, so the ones sent there start with a mention of their
+/// recipient, like `@123 `, and the ones mentioning someone else are skipped.
+pub(crate) struct Tunnel {
+    transport: Arc<dyn MessengerTransport>,
+    user_id: u32,
+    outbound_tx: Sender<(u32, Frame)>,
+    static_key: Option<StaticSecret>,
+    allowed_users: Option<HashSet<u32>>,
+    document_threshold: usize,
+    auto_delete: bool,
+    sessions: HashMap<u32, Session>,
+    // Chat each peer with a session is reached in.
+    chats: HashMap<u32, Chat>,
+    // Streams carried by the session of each peer, so they can be reset
+    // locally when the session goes away.
+    streams: HashMap<u32, HashMap<u32, StreamEnds>>,
+    // Messages handled from each peer that wait for the session to
+    // acknowledge what they carried before they are deleted, with the
+    // sequence number to acknowledge.
+    handled: HashMap<u32, Vec<(Chat, MessageId, Option<u64>)>>,
+}
+
+/// Which sides of a stream sent their FIN.
+#[derive(Debug, Default)]
+struct StreamEnds {
+    sent_fin: bool,
+    received_fin: bool,
+}
+
+impl Tunnel {
+    pub(crate) fn new(
+        transport: Arc<dyn MessengerTransport>,
+        user_id: u32,
+        outbound_tx: Sender<(u32, Frame)>,
+        static_key: Option<StaticSecret>,
+        allowed_users: Option<HashSet<u32>>,
+        document_threshold: usize,
+        auto_delete: bool,
+    ) -> Tunnel {
+        Tunnel {
+            transport,
+            user_id,
+            outbound_tx,
+            static_key,
+            allowed_users,
+            document_threshold,
+            auto_delete,
+            sessions: HashMap::new(),
+            chats: HashMap::new(),
+            streams: HashMap::new(),
+            handled: HashMap::new(),
+        }
+    }
+
+    /// Starts the handshake with the server in `chat`, proposing `codec` for
+    /// the session.
+    pub(crate) fn connect(
+        &mut self,
+        server_user_id: u32,
+        chat: Chat,
+        server_key: PublicKey,
+        codec: &'static dyn PayloadCodec,
+    ) {
+        let (session, hello) = Session::connect(server_key, codec, self.document_threshold);
+        self.sessions.insert(server_user_id, session);
+        self.chats.insert(server_user_id, chat);
+
+        info!("handshaking with server #{} in {}", server_user_id, chat);
+        self.send_message(server_user_id, Outgoing::Text(hello));
+    }
+
+    /// Packs the frames read from local sockets into as few messages as
+    /// possible and sends them to their peers.
+    pub(crate) fn send_frames(&mut self, frames: Vec<(u32, Frame)>) {
+        let mut frames_by_peer: HashMap<u32, Vec<Frame>> = HashMap::new();
+        for (peer, frame) in frames {
+            debug!(
+                "received frame for user #{} from socket : {:?}",
+                peer, frame
+            );
+            frames_by_peer.entry(peer).or_default().push(frame);
+        }
+
+        for (peer, frames) in frames_by_peer {
+            if !self.sessions.contains_key(&peer) {
+                error!("sending message to user #{} but there is no session", peer);
+                continue;
+            }
+            for frame in &frames {
+                self.track_stream(peer, frame, true);
+            }
+            let session = self.sessions.get_mut(&peer).unwrap();
+            let stream_ids: HashSet<u32> = frames.iter().map(Frame::stream_id).collect();
+            let messages = match session.seal(frames) {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error!(
+                        "could not send frames to user #{}, resetting their streams : {}",
+                        peer, err
+                    );
+                    let resets = stream_ids
+                        .iter()
+                        .map(|&stream_id| Frame::Reset { stream_id })
+                        .collect();
+                    let messages = session.seal(resets).unwrap_or_default();
+                    if let Some(streams) = self.streams.get_mut(&peer) {
+                        streams.retain(|stream_id, _| !stream_ids.contains(stream_id));
+                    }
+                    self.reset_local_streams(peer, stream_ids);
+                    messages
+                }
+            };
+            for message in messages {
+                self.send_message(peer, message);
+            }
+        }
+    }
+
+    /// Keeps track of the streams a frame sent to or received from `peer`
+    /// opens or closes. Datagram flows end on their own when idle.
+    fn track_stream(&mut self, peer: u32, frame: &Frame, sent: bool) {
+        let streams = self.streams.entry(peer).or_default();
+        match *frame {
+            Frame::Open { stream_id }
+            | Frame::Connect { stream_id, .. }
+            | Frame::Data { stream_id, .. } => {
+                streams.entry(stream_id).or_default();
+            }
+            Frame::Fin { stream_id } => {
+                let ends = streams.entry(stream_id).or_default();
+                if sent {
+                    ends.sent_fin = true;
+                } else {
+                    ends.received_fin = true;
+                }
+                if ends.sent_fin && ends.received_fin {
+                    streams.remove(&stream_id);
+                }
+            }
+            Frame::Status { stream_id, status } if status != StreamStatus::Connected => {
+                streams.remove(&stream_id);
+            }
+            Frame::Reset { stream_id } => {
+                streams.remove(&stream_id);
+            }
+            Frame::Status { .. } | Frame::Datagram { .. } => {}
+        }
+    }
+
+    /// Resets the local side of every stream the session of `peer` carried,
+    /// once that session is gone.
+    fn reset_streams(&mut self, peer: u32) {
+        if let Some(streams) = self.streams.remove(&peer) {
+            if !streams.is_empty() {
+                warn!(
+                    "resetting {} streams of the previous session of user #{}",
+                    streams.len(),
+                    peer
+                );
+            }
+            self.reset_local_streams(peer, streams.into_keys());
+        }
+    }
+
+    /// Closes the local side of streams the peer is not going to carry.
+    fn reset_local_streams(&self, peer: u32, stream_ids: impl IntoIterator<Item = u32>) {
+        for stream_id in stream_ids {
+            if let Err(err) = self
+                .outbound_tx
+                .try_send((peer, Frame::Reset { stream_id }))
+            {
+                error!("could not reset stream #{} : {}", stream_id, err);
+            }
+        }
+    }
+
+    /// Handles a text or document message received from a peer.
+    ///
+    /// With `auto_delete`, messages are deleted from the chat for all of its
+    /// members once the session acknowledged the packets they carried, so the
+    /// chat doesn't keep the traffic.
+    pub(crate) async fn receive(&mut self, received: Received) -> Result<(), Error> {
+        let res = self
+            .receive_message(received.sender_id, received.chat, received.message)
+            .await;
+        if self.auto_delete && matches!(res, Ok(true)) {
+            let sequence = self
+                .sessions
+                .get(&received.sender_id)
+                .and_then(Session::last_opened);
+            self.handled.entry(received.sender_id).or_default().push((
+                received.chat,
+                received.id,
+                sequence,
+            ));
+            self.delete_acknowledged();
+        }
+        res.map(|_| ())
+    }
+
+    /// Deletes the handled messages whose packets were acknowledged, or whose
+    /// session is gone.
+    fn delete_acknowledged(&mut self) {
+        let mut deleted: HashMap<Chat, Vec<MessageId>> = HashMap::new();
+        for (peer, handled) in self.handled.iter_mut() {
+            let session = self.sessions.get(peer);
+            handled.retain(|&(chat, id, sequence)| {
+                let acknowledged = match (session, sequence) {
+                    (Some(session), Some(sequence)) => session.is_acknowledged(sequence),
+                    _ => true,
+                };
+                if acknowledged {
+                    deleted.entry(chat).or_default().push(id);
+                }
+                !acknowledged
+            });
+        }
+        self.handled.retain(|_, handled| !handled.is_empty());
+        for (chat, ids) in deleted {
+            self.transport.queue_delete(chat, ids);
+        }
+    }
+
+    /// Deletes the handled messages of a session that was replaced, whose
+    /// sequence numbers the new session doesn't know.
+    fn delete_handled(&mut self, peer: u32) {
+        if let Some(handled) = self.handled.get_mut(&peer) {
+            for (_, _, sequence) in handled.iter_mut() {
+                *sequence = None;
+            }
+        }
+        self.delete_acknowledged();
+    }
+
+    /// Returns whether the message was for this account and handled.
+    async fn receive_message(
+        &mut self,
+        sender_id: u32,
+        chat: Chat,
+        msg: Message,
+    ) -> Result<bool, Error> {
+        let in_group = matches!(chat, Chat::Group(_));
+        if in_group {
+            let is_for_me = match &msg {
+                Message::Text(text) => text.lines().any(|line| self.unmention(line).is_some()),
+                Message::Document(document) => self.unmention(&document.name).is_some(),
+            };
+            if !is_for_me {
+                trace!(
+                    "skipping message of user #{} for another member of {}",
+                    sender_id,
+                    chat
+                );
+                return Ok(false);
+            }
+        }
+
+        if let Some(allowed_users) = &self.allowed_users {
+            if !allowed_users.contains(&sender_id) {
+                warn!(
+                    "dropping message from user #{} who is not allowed to use the tunnel",
+                    sender_id
+                );
+                return Ok(false);
+            }
+        }
+
+        match msg {
+            Message::Text(text) => {
+                // The outbox joins packets queued together with new lines.
+                let mut res = Ok(true);
+                for line in text.lines() {
+                    let line = match (in_group, self.unmention(line)) {
+                        (false, _) => line,
+                        (true, Some(line)) => line,
+                        (true, None) => continue,
+                    };
+                    let bytes = match self.sessions.get(&sender_id) {
+                        Some(session) => session.decode(line),
+                        None => Base64.decode(line),
+                    };
+                    if let Err(err) = match bytes {
+                        Ok(bytes) => self.receive_packet(sender_id, chat, &bytes).await,
+                        Err(err) => Err(err),
+                    } {
+                        res = Err(err);
+                    }
+                }
+                res
+            }
+            Message::Document(document) => {
+                debug!(
+                    "downloading document {} of {} bytes from user #{}",
+                    document.name, document.size, sender_id
+                );
+                let bytes = self.transport.download_file(&document).await?;
+                self.receive_packet(sender_id, chat, &bytes).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Strips the mention of this account from a line or document name sent
+    /// in a group, or returns `None` if it is for another member.
+    fn unmention<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let (mention, rest) = text.strip_prefix('@')?.split_once(' ')?;
+        if mention.parse::<u32>().ok() == Some(self.user_id) {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// Handles a decoded packet received from `sender_id` in `chat`.
+    async fn receive_packet(
+        &mut self,
+        sender_id: u32,
+        chat: Chat,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        info!("received msg from client : {}", dump_hex(bytes));
+
+        match Packet::parse(bytes)? {
+            Packet::ClientHello(client_key, proposal) => {
+                let static_key = self.static_key.as_ref().ok_or_else(|| {
+                    Error::CryptoError("received client hello in client mode".to_string())
+                })?;
+
+                if let Some(session) = self.sessions.get(&sender_id) {
+                    if session.is_accepted_from(&client_key) {
+                        // The server hello was lost, the session is still good.
+                        debug!("client #{} sent its hello again", sender_id);
+                        let hello = session.hello().to_string();
+                        self.send_message(sender_id, Outgoing::Text(hello));
+                        return Ok(());
+                    }
+                }
+
+                let (session, hello) =
+                    Session::accept(static_key, client_key, proposal, self.document_threshold);
+                info!(
+                    "established session with client #{} using {}",
+                    sender_id,
+                    session.codec().name()
+                );
+                if self.sessions.insert(sender_id, session).is_some() {
+                    warn!("client #{} replaced its previous session", sender_id);
+                    self.reset_streams(sender_id);
+                    self.delete_handled(sender_id);
+                }
+                self.chats.insert(sender_id, chat);
+
+                self.send_message(sender_id, Outgoing::Text(hello));
+            }
+            Packet::ServerHello(server_key, confirmation) => {
+                if self.static_key.is_some() {
+                    return Err(Error::CryptoError(
+                        "received server hello in server mode".to_string(),
+                    ));
+                }
+                let session = self.session(sender_id)?;
+
+                let pending = session.finish(server_key, confirmation)?;
+                info!(
+                    "established session with server #{} using {}",
+                    sender_id,
+                    session.codec().name()
+                );
+                for message in pending {
+                    self.send_message(sender_id, message);
+                }
+            }
+            Packet::Sealed(packet) => {
+                let (frames, ready) = self.session(sender_id)?.open(packet)?;
+                for message in ready {
+                    self.send_message(sender_id, message);
+                }
+                for frame in frames {
+                    self.track_stream(sender_id, &frame, false);
+                    if let Err(err) = self.outbound_tx.send((sender_id, frame)).await {
+                        error!("could not send message message to socket : {:?}", err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn session(&mut self, sender_id: u32) -> Result<&mut Session, Error> {
+        self.sessions.get_mut(&sender_id).ok_or_else(|| {
+            Error::CryptoError(format!(
+                "received packet from user #{} without session",
+                sender_id
+            ))
+        })
+    }
+
+    /// Sends again what the peers did not acknowledge in time, and the
+    /// acknowledgements that had nothing to travel with. Handshakes again with
+    /// a server that stopped acknowledging anything.
+    pub(crate) fn tick(&mut self) {
+        let now = Instant::now();
+        let mut messages = Vec::new();
+        let mut restarted_peers = Vec::new();
+        for (&peer, session) in self.sessions.iter_mut() {
+            if let Some((restarted, hello)) = session.restart() {
+                warn!(
+                    "server #{} does not acknowledge messages anymore, handshaking again",
+                    peer
+                );
+                *session = restarted;
+                restarted_peers.push(peer);
+                messages.push((peer, Outgoing::Text(hello)));
+                continue;
+            }
+            messages.extend(session.tick(now).into_iter().map(|message| (peer, message)));
+        }
+        for peer in restarted_peers {
+            self.reset_streams(peer);
+            self.delete_handled(peer);
+        }
+        for (peer, message) in messages {
+            self.send_message(peer, message);
+        }
+        self.delete_acknowledged();
+    }
+
+    /// Reacts to a message the outbox could not send to `chat`.
+    pub(crate) fn send_failed(&mut self, chat: Chat, err: Error) {
+        match err {
+            Error::UnknownChat(_) => {
+                warn!("{} is gone, dropping its sessions", chat);
+                let gone: Vec<u32> = self
+                    .chats
+                    .iter()
+                    .filter(|&(_, &peer_chat)| peer_chat == chat)
+                    .map(|(&peer, _)| peer)
+                    .collect();
+                for peer in gone {
+                    self.sessions.remove(&peer);
+                    self.chats.remove(&peer);
+                    self.handled.remove(&peer);
+                    self.reset_streams(peer);
+                }
+            }
+            err => error!("could not send message to {} : {}", chat, err),
+        }
+    }
+
+    fn send_message(&self, recipient: u32, message: Outgoing) {
+        let chat = match self.chats.get(&recipient) {
+            Some(&chat) => chat,
+            None => {
+                error!(
+                    "sending message to user #{} but there is no chat",
+                    recipient
+                );
+                return;
+            }
+        };
+        let mention = match chat {
+            Chat::User(_) => String::new(),
+            Chat::Group(_) => format!("@{} ", recipient),
+        };
+        match message {
+            Outgoing::Text(text) => self
+                .transport
+                .queue_message(chat, format!("{}{}", mention, text)),
+            Outgoing::Document(content) => {
+                let name = format!("{}{:016x}.bin", mention, rand::random::<u64>());
+                self.transport.queue_document(chat, name, content)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use crate::loopback::{Conditions, Network};
+    use async_std::channel::{unbounded, Receiver};
+    use std::time::Duration;
+
+    const SERVER_ID: u32 = 1;
+    const CLIENT_ID: u32 = 2;
+
+    /// Tunnel of an account, with the messages it received and the frames it
+    /// handed to its sockets.
+    struct Side {
+        tunnel: Tunnel,
+        received: Receiver<Received>,
+        sockets: Receiver<(u32, Frame)>,
+    }
+
+    impl Side {
+        fn new(network: &Arc<Network>, user_id: u32, static_key: Option<StaticSecret>) -> Side {
+            let transport: Arc<dyn MessengerTransport> = Arc::new(network.join(user_id));
+            let (received_tx, received) = unbounded();
+            let updates = transport.clone();
+            tokio::spawn(async move { updates.subscribe_to_updates(received_tx).await });
+            let outbox = transport.clone();
+            tokio::spawn(async move {
+                let (failed_tx, _failed_rx) = unbounded();
+                outbox.run_outbox(failed_tx).await
+            });
+
+            let (sockets_tx, sockets) = unbounded();
+            let tunnel = Tunnel::new(transport, user_id, sockets_tx, static_key, None, 0, false);
+            Side {
+                tunnel,
+                received,
+                sockets,
+            }
+        }
+
+        async fn next_received(&self) -> Received {
+            tokio::time::timeout(Duration::from_secs(5), self.received.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+
+        /// Hands the next message sent to this account to its tunnel, and
+        /// returns its id.
+        async fn receive_next(&mut self) -> MessageId {
+            let received = self.next_received().await;
+            let id = received.id;
+            self.tunnel.receive(received).await.unwrap();
+            id
+        }
+    }
+
+    #[tokio::test]
+    async fn resets_streams_of_replaced_sessions() {
+        let network = Network::new(Conditions::default(), 0);
+        let static_key = crypto::generate_static_key();
+        let server_key = PublicKey::from(&static_key);
+        let mut server = Side::new(&network, SERVER_ID, Some(static_key));
+        let mut client = Side::new(&network, CLIENT_ID, None);
+        let chat = Chat::User(SERVER_ID);
+
+        client.tunnel.connect(SERVER_ID, chat, server_key, &Base64);
+        server.receive_next().await;
+        client.receive_next().await;
+        let open = Frame::Open { stream_id: 7 };
+        client.tunnel.send_frames(vec![(SERVER_ID, open.clone())]);
+        server.receive_next().await;
+        assert_eq!(server.sockets.try_recv().unwrap(), (CLIENT_ID, open));
+
+        // A client that restarted handshakes again with a new key.
+        client.tunnel.connect(SERVER_ID, chat, server_key, &Base64);
+        server.receive_next().await;
+        assert_eq!(
+            server.sockets.try_recv().unwrap(),
+            (CLIENT_ID, Frame::Reset { stream_id: 7 })
+        );
+        assert!(server.sockets.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deletes_messages_once_acknowledged() {
+        let network = Network::new(Conditions::default(), 0);
+        let static_key = crypto::generate_static_key();
+        let server_key = PublicKey::from(&static_key);
+        let mut server = Side::new(&network, SERVER_ID, Some(static_key));
+        let mut client = Side::new(&network, CLIENT_ID, None);
+        server.tunnel.auto_delete = true;
+
+        client
+            .tunnel
+            .connect(SERVER_ID, Chat::User(SERVER_ID), server_key, &Base64);
+        let hello = server.receive_next().await;
+        client.receive_next().await;
+        assert_eq!(network.deleted(), vec![hello]);
+
+        client
+            .tunnel
+            .send_frames(vec![(SERVER_ID, Frame::Open { stream_id: 7 })]);
+        let sealed = server.receive_next().await;
+        assert_eq!(network.deleted(), vec![hello]);
+
+        // The acknowledgement goes out on its own after a short delay.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.tunnel.tick();
+        assert_eq!(network.deleted(), vec![hello, sealed]);
+    }
+
+    #[tokio::test]
+    async fn ignores_users_who_are_not_allowed() {
+        let network = Network::new(Conditions::default(), 0);
+        let static_key = crypto::generate_static_key();
+        let server_key = PublicKey::from(&static_key);
+        let mut server = Side::new(&network, SERVER_ID, Some(static_key));
+        let mut client = Side::new(&network, CLIENT_ID, None);
+        server.tunnel.allowed_users = Some(vec![CLIENT_ID + 1].into_iter().collect());
+        server.tunnel.auto_delete = true;
+
+        client
+            .tunnel
+            .connect(SERVER_ID, Chat::User(SERVER_ID), server_key, &Base64);
+        let received = server.next_received().await;
+        let handled = server
+            .tunnel
+            .receive_message(received.sender_id, received.chat, received.message.clone())
+            .await;
+        assert!(matches!(handled, Ok(false)));
+
+        server.tunnel.receive(received).await.unwrap();
+        assert!(server.tunnel.sessions.is_empty());
+        assert!(network.deleted().is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use async_std::task;
+use futures::future::Future;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tracing::{debug, error, info};
+
+use crate::frame::Frame;
+use crate::socket::Routes;
+use crate::socks5;
+
+/// Largest UDP payload sent through the tunnel, so a datagram frame always
+/// fits in a single message.
+const MAX_DATAGRAM_SIZE: usize = 2048;
+/// Flows without packets for this long are forgotten on both sides.
+#[cfg(not(test))]
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(test)]
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Server side of a UDP flow: sends its packets from a socket of its own and
+/// sends back what comes to that socket, until the flow is idle.
+///
+/// The socket is dual-stack when IPv6 is available, so one flow can reach
+/// targets of both families. Packets only go to the targets the client asks
+/// for with `open_targets`, otherwise they can only reach the upstream.
+pub(crate) async fn relay_flow(
+    peer: u32,
+    flow_id: u32,
+    upstream: Vec<SocketAddr>,
+    open_targets: bool,
+    inbound_tx: Sender<(u32, Frame)>,
+    outbound_rx: Receiver<Frame>,
+) {
+    let socket = match UdpSocket::bind("[::]:0").await {
+        Ok(socket) => Ok(socket),
+        Err(_) => UdpSocket::bind("0.0.0.0:0").await,
+    };
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("could not bind socket for flow #{} : {:?}", flow_id, err);
+            return;
+        }
+    };
+    let dual_stack = matches!(socket.local_addr(), Ok(SocketAddr::V6(_)));
+    info!("relaying flow #{} of user #{}", flow_id, peer);
+
+    let mut buffer = vec![0; u16::MAX as usize];
+    loop {
+        select! {
+            res = outbound_rx.recv() => {
+                let (target, payload) = match res {
+                    Ok(Frame::Datagram { target, payload, .. }) => (target, payload),
+                    Ok(_) => continue,
+                    Err(_) => break,
+                };
+                let targets = if target.is_empty() {
+                    upstream.clone()
+                } else if open_targets {
+                    resolve(&target).await
+                } else {
+                    debug!("dropping datagram of flow #{} to {}, open_targets is off", flow_id, target);
+                    continue;
+                };
+                let target = targets.into_iter().find_map(|target| reachable(target, dual_stack));
+                match target {
+                    Some(target) => {
+                        if let Err(err) = socket.send_to(&payload, target).await {
+                            debug!("could not send datagram of flow #{} : {:?}", flow_id, err);
+                        }
+                    }
+                    None => debug!("dropping datagram of flow #{} to unknown target", flow_id),
+                }
+            }
+            res = socket.recv_from(&mut buffer) => {
+                let (size, source) = match res {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("error reading from flow #{} : {:?}", flow_id, err);
+                        break;
+                    }
+                };
+                if size > MAX_DATAGRAM_SIZE {
+                    debug!("dropping datagram of {} bytes from {}", size, source);
+                    continue;
+                }
+                // Replies from the upstream go back as replies from the upstream.
+                let source = unmapped(source);
+                let target = if upstream.contains(&source) {
+                    String::new()
+                } else {
+                    source.to_string()
+                };
+                let frame = Frame::Datagram { flow_id, target, payload: buffer[..size].to_vec() };
+                if inbound_tx.send((peer, frame)).await.is_err() {
+                    break;
+                }
+            }
+            _ = task::sleep(FLOW_IDLE_TIMEOUT) => break,
+        }
+    }
+    debug!("flow #{} of user #{} is idle", flow_id, peer);
+}
+
+/// Client side of a UDP listener: every address sending to `socket` gets a
+/// flow of its own, until it is idle or `until` completes.
+///
+/// With `socks`, packets carry the header of the SOCKS5 UDP relay with their
+/// target. Otherwise they all go to the server's upstream.
+pub(crate) async fn serve<F>(
+    socket: UdpSocket,
+    server: u32,
+    socks: bool,
+    inbound_tx: Sender<(u32, Frame)>,
+    routes: Routes,
+    next_flow_id: &AtomicU32,
+    until: F,
+) where
+    F: Future<Output = ()>,
+{
+    struct Flow {
+        id: u32,
+        last_active: Instant,
+    }
+
+    let (tx, rx) = unbounded();
+    let mut flows: HashMap<SocketAddr, Flow> = HashMap::new();
+    let mut sources: HashMap<u32, SocketAddr> = HashMap::new();
+    let mut buffer = vec![0; u16::MAX as usize];
+    futures::pin_mut!(until);
+    loop {
+        select! {
+            _ = &mut until => break,
+            res = socket.recv_from(&mut buffer) => {
+                let (size, source) = match res {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("error reading from UDP socket : {:?}", err);
+                        break;
+                    }
+                };
+                let (target, payload) = if socks {
+                    match socks5::parse_datagram(&buffer[..size]) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            debug!("dropping datagram from {} : {}", source, err);
+                            continue;
+                        }
+                    }
+                } else {
+                    (String::new(), &buffer[..size])
+                };
+                if payload.len() > MAX_DATAGRAM_SIZE {
+                    debug!("dropping datagram of {} bytes from {}", payload.len(), source);
+                    continue;
+                }
+
+                let flow_id = match flows.get_mut(&source) {
+                    Some(flow) => {
+                        flow.last_active = Instant::now();
+                        flow.id
+                    }
+                    None => {
+                        let id = next_flow_id.fetch_add(1, Ordering::Relaxed);
+                        debug!("flow #{} started from {}", id, source);
+                        routes.lock().await.insert((server, id), tx.clone());
+                        sources.insert(id, source);
+                        flows.insert(source, Flow { id, last_active: Instant::now() });
+                        id
+                    }
+                };
+                let frame = Frame::Datagram { flow_id, target, payload: payload.to_vec() };
+                if let Err(err) = inbound_tx.send((server, frame)).await {
+                    error!("could not send datagram to inbound_tx : {:?}", err);
+                    break;
+                }
+            }
+            Ok(frame) = rx.recv() => {
+                let (flow_id, target, payload) = match frame {
+                    Frame::Datagram { flow_id, target, payload } => (flow_id, target, payload),
+                    _ => continue,
+                };
+                let source = match sources.get(&flow_id) {
+                    Some(source) => *source,
+                    None => continue,
+                };
+                let packet = if socks {
+                    socks5::datagram(&target, &payload)
+                } else {
+                    payload
+                };
+                if let Err(err) = socket.send_to(&packet, source).await {
+                    debug!("could not send datagram to {} : {:?}", source, err);
+                }
+                if let Some(flow) = flows.get_mut(&source) {
+                    flow.last_active = Instant::now();
+                }
+            }
+            _ = task::sleep(FLOW_IDLE_TIMEOUT) => {}
+        }
+
+        let idle: Vec<SocketAddr> = flows
+            .iter()
+            .filter(|(_, flow)| flow.last_active.elapsed() >= FLOW_IDLE_TIMEOUT)
+            .map(|(source, _)| *source)
+            .collect();
+        for source in idle {
+            if let Some(flow) = flows.remove(&source) {
+                debug!("flow #{} from {} is idle", flow.id, source);
+                sources.remove(&flow.id);
+                routes.lock().await.remove(&(server, flow.id));
+            }
+        }
+    }
+
+    let mut routes = routes.lock().await;
+    for id in sources.keys() {
+        routes.remove(&(server, *id));
+    }
+}
+
+async fn resolve(target: &str) -> Vec<SocketAddr> {
+    match target.to_socket_addrs().await {
+        Ok(addrs) => addrs.collect(),
+        Err(err) => {
+            debug!("could not resolve {} : {:?}", target, err);
+            Vec::new()
+        }
+    }
+}
+
+/// Address to send to for `target` from a socket, if it can reach it at all.
+fn reachable(target: SocketAddr, dual_stack: bool) -> Option<SocketAddr> {
+    match target {
+        SocketAddr::V4(v4) if dual_stack => Some(SocketAddr::new(
+            IpAddr::V6(v4.ip().to_ipv6_mapped()),
+            v4.port(),
+        )),
+        SocketAddr::V6(_) if !dual_stack => None,
+        target => Some(target),
+    }
+}
+
+/// Undoes [`reachable`] for addresses seen by a dual-stack socket.
+fn unmapped(source: SocketAddr) -> SocketAddr {
+    match source {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => source,
+        },
+        source => source,
+    }
+}
@@ -1,12 +1,48 @@
 use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 
 pub(crate) fn dump_hex(buffer: &[u8]) -> String {
     let mut hex_bytes = String::with_capacity(2 * buffer.len());
     for (i, &byte) in buffer.iter().enumerate() {
         if i % 16 == 0 {
-            std::write!(hex_bytes, "\n").expect("Dumping hex data failed");
+            std::writeln!(hex_bytes).expect("Dumping hex data failed");
         }
         std::write!(hex_bytes, "{:02X} ", byte).expect("Dumping hex data failed");
     }
     hex_bytes
 }
+
+/// Resolves `host` to every address it stands for, with `port`. IPv6 addresses
+/// may come with or without brackets, shadowsocks passes them without.
+pub(crate) fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host, port).to_socket_addrs()?.collect())
+}
+
+/// Same as [`resolve`], with an address like `host:port` or `[::1]:port`.
+pub(crate) fn resolve_addrs(addrs: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("bad address {}", addrs),
+        )
+    };
+    let (host, port) = addrs.rsplit_once(':').ok_or_else(invalid)?;
+    resolve(host, port.parse().map_err(|_| invalid())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_addresses() {
+        let v4: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let v6: SocketAddr = "[::1]:1080".parse().unwrap();
+        assert_eq!(resolve("127.0.0.1", 1080).unwrap(), vec![v4]);
+        assert_eq!(resolve("::1", 1080).unwrap(), vec![v6]);
+        assert_eq!(resolve_addrs("[::1]:1080").unwrap(), vec![v6]);
+        assert!(resolve_addrs("localhost").is_err());
+    }
+}
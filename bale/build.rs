@@ -7,6 +7,7 @@ fn main() {
                 "proto/auth.proto",
                 "proto/messaging.proto",
                 "proto/maviz.proto",
+                "proto/files.proto",
             ],
             &["proto"],
         )
@@ -0,0 +1,106 @@
+use grpc_web_client::{Client, Encoding};
+use tokio::sync::watch;
+
+use crate::outbox::Outbox;
+use crate::{BaleClient, Error, LoginStatus, RateLimit};
+
+const DEFAULT_ENDPOINT: &str = "https://next-api.bale.ai";
+const DEFAULT_API_KEY: &str = "C28D46DC4C3A7A26564BFCC48B929086A95C93C98E789A19847BEE8627DE4E7D";
+const DEFAULT_CLIENT_VERSION: i32 = 4;
+const DEFAULT_USER_AGENT: &str = "Firefox, macOS";
+
+/// Settings of the connection to Bale, defaulting to what the web client of
+/// `next-api.bale.ai` uses.
+///
+/// Mirror endpoints, a local server for tests or new keys when Bale rotates
+/// them can be set here.
+#[derive(Debug, Clone)]
+pub struct BaleClientBuilder {
+    phone_number: u64,
+    endpoint: String,
+    api_key: String,
+    client_version: i32,
+    user_agent: String,
+    root_certificates: Vec<Vec<u8>>,
+    accept_invalid_certs: bool,
+}
+
+impl BaleClientBuilder {
+    pub(crate) fn new(phone_number: u64) -> Self {
+        BaleClientBuilder {
+            phone_number,
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            api_key: DEFAULT_API_KEY.to_string(),
+            client_version: DEFAULT_CLIENT_VERSION,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            root_certificates: Vec::new(),
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// Base URL of the gRPC-Web API, like `https://next-api.bale.ai`.
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Key of the web client, sent when logging in.
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = api_key.into();
+        self
+    }
+
+    pub fn client_version(mut self, client_version: i32) -> Self {
+        self.client_version = client_version;
+        self
+    }
+
+    /// Name of the client shown in the sessions of the account, like
+    /// `Firefox, macOS`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Trusts the PEM encoded certificate on top of the system ones.
+    pub fn root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Accepts any certificate, for endpoints with a self-signed one.
+    pub fn accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    pub fn build(self) -> Result<BaleClient, Error> {
+        let mut http_client =
+            reqwest::Client::builder().danger_accept_invalid_certs(self.accept_invalid_certs);
+        for pem in &self.root_certificates {
+            let certificate = reqwest::Certificate::from_pem(pem)
+                .map_err(|err| Error::InvalidSettings(format!("bad certificate : {}", err)))?;
+            http_client = http_client.add_root_certificate(certificate);
+        }
+        let http_client = http_client
+            .build()
+            .map_err(|err| Error::InvalidSettings(err.to_string()))?;
+
+        Ok(BaleClient {
+            client: Client::new_with_http_client(
+                self.endpoint,
+                Encoding::Base64,
+                http_client.clone(),
+            ),
+            http_client,
+            api_key: self.api_key,
+            client_version: self.client_version,
+            user_agent: self.user_agent,
+            login_status: watch::channel(LoginStatus::WaitingForNumber).0,
+            phone_number: self.phone_number,
+            credential_store: None,
+            rate_limit: RateLimit::default(),
+            outbox: Outbox::new(),
+        })
+    }
+}
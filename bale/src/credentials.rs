@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Login of a phone number, saved so restarts don't need a new SMS code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub jwt: String,
+    pub user_id: u32,
+}
+
+/// Directory holding one credentials file per phone number.
+///
+/// Files are only readable by their owner, since the JWT is enough to use the account.
+#[derive(Debug, Clone)]
+pub struct CredentialStore {
+    dir: PathBuf,
+}
+
+impl CredentialStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        CredentialStore { dir: dir.into() }
+    }
+
+    /// `~/.bale-proxy`, if the home directory is known.
+    pub fn default_location() -> Option<Self> {
+        std::env::var_os("HOME")
+            .map(|home| CredentialStore::new(PathBuf::from(home).join(".bale-proxy")))
+    }
+
+    /// Directory of the files, where other secrets of the account can be kept.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn load(&self, phone_number: u64) -> Option<Credentials> {
+        let path = self.path(phone_number);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) => {
+                debug!("no saved credentials in {} : {}", path.display(), err);
+                return None;
+            }
+        };
+        match serde_json::from_slice(&content) {
+            Ok(credentials) => Some(credentials),
+            Err(err) => {
+                warn!("ignoring bad credentials file {} : {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, phone_number: u64, credentials: &Credentials) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let path = self.path(phone_number);
+        let mut file = options.open(&path)?;
+        // The mode only applies to new files.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(&serde_json::to_vec(credentials)?)?;
+        debug!("saved credentials to {}", path.display());
+        Ok(())
+    }
+
+    fn path(&self, phone_number: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", phone_number))
+    }
+}
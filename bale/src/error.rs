@@ -0,0 +1,88 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+use crate::Peer;
+
+/// Failure of a request to Bale.
+#[derive(Debug)]
+pub enum Error {
+    /// There is no valid session, log in again.
+    AuthExpired,
+    /// Too many requests, with the delay the server asked for if any.
+    RateLimited(Option<Duration>),
+    /// The server could not be reached or the connection broke.
+    Network(String),
+    /// The server replied with something this client does not understand.
+    MalformedResponse(String),
+    /// The user or group does not exist or can't be messaged.
+    UnknownPeer(Peer),
+    /// Any other error returned by the server.
+    Rpc(Box<tonic::Status>),
+    /// The client could not be built with the given settings.
+    InvalidSettings(String),
+}
+
+impl Error {
+    /// Whether the same request may succeed if it is sent again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::RateLimited(_) | Error::Network(_))
+    }
+
+    pub(crate) fn malformed(what: &str) -> Error {
+        Error::MalformedResponse(format!("{} is missing from the response", what))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::AuthExpired => write!(f, "session expired"),
+            Error::RateLimited(Some(delay)) => write!(f, "rate limited for {:?}", delay),
+            Error::RateLimited(None) => write!(f, "rate limited"),
+            Error::Network(ref e) => write!(f, "network error : {}", e),
+            Error::MalformedResponse(ref e) => write!(f, "malformed response : {}", e),
+            Error::UnknownPeer(peer) => write!(f, "unknown {}", peer),
+            Error::Rpc(ref status) => write!(f, "{} : {}", status.code(), status.message()),
+            Error::InvalidSettings(ref e) => write!(f, "invalid settings : {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Rpc(ref status) => Some(status.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Error {
+        match status.code() {
+            tonic::Code::Unauthenticated => Error::AuthExpired,
+            tonic::Code::ResourceExhausted => Error::RateLimited(retry_after(&status)),
+            // Transport errors of the gRPC-Web client end up as unknown errors.
+            tonic::Code::Unavailable
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::Cancelled
+            | tonic::Code::Unknown => Error::Network(status.message().to_string()),
+            _ => Error::Rpc(Box::new(status)),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        match err.status().map(|status| status.as_u16()) {
+            Some(401) | Some(403) => Error::AuthExpired,
+            Some(429) => Error::RateLimited(None),
+            _ => Error::Network(err.to_string()),
+        }
+    }
+}
+
+fn retry_after(status: &tonic::Status) -> Option<Duration> {
+    let seconds = status.metadata().get("retry-after")?.to_str().ok()?;
+    seconds.parse().ok().map(Duration::from_secs)
+}
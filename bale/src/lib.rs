@@ -2,20 +2,89 @@ tonic::include_proto!("bale.v1");
 tonic::include_proto!("bale.auth.v1");
 tonic::include_proto!("bale.messaging.v2");
 tonic::include_proto!("bale.maviz.v1");
+tonic::include_proto!("bale.files.v1");
+
+mod builder;
+mod credentials;
+mod error;
+mod outbox;
+mod peer;
+
+pub use builder::BaleClientBuilder;
+pub use credentials::{CredentialStore, Credentials};
+pub use error::Error;
+pub use outbox::{OutboxStats, RateLimit};
+pub use peer::Peer;
 
 use async_std::channel::Sender;
 use serde::Deserialize;
-use std::collections::HashMap;
-use tracing::{debug, error, trace};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+use outbox::{Outbox, Outgoing, TokenBucket};
+use tracing::{debug, error, info, trace, warn};
 
-use grpc_web_client::{Client, Encoding};
+use grpc_web_client::Client;
 
-const API_KEY: &str = "C28D46DC4C3A7A26564BFCC48B929086A95C93C98E789A19847BEE8627DE4E7D";
+/// Maximum number of characters Bale accepts in a single text message.
+pub const MAX_TEXT_MESSAGE_LENGTH: usize = 4096;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Number of missed messages fetched per GetDifference call.
+const DIFFERENCE_LIMIT: u32 = 100;
+// Number of recent message rids remembered to drop duplicates after a reconnect.
+const SEEN_MESSAGES: usize = 1024;
+// Delay after being rate limited, if the server didn't say how long to wait.
+const RATE_LIMIT_DELAY: Duration = Duration::from_secs(5);
+// Number of times a queued message is sent again after a network error.
+const MAX_SEND_ATTEMPTS: u32 = 5;
 
 pub struct BaleClient {
     client: Client,
-    login_status: LoginStatus,
+    http_client: reqwest::Client,
+    api_key: String,
+    client_version: i32,
+    user_agent: String,
+    login_status: watch::Sender<LoginStatus>,
     phone_number: u64,
+    credential_store: Option<CredentialStore>,
+    rate_limit: RateLimit,
+    outbox: Outbox,
+}
+
+/// Message received from another user, with the chat it was sent in and
+/// what identifies it there.
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub peer: Peer,
+    pub sender_id: u32,
+    pub id: MessageId,
+    pub message: Message,
+}
+
+/// Identifies a message in a chat, to delete it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageId {
+    pub rid: u64,
+    pub date: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Text(String),
+    Document(Document),
+}
+
+/// File attached to a document message.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub file_id: i64,
+    pub access_hash: i64,
+    pub size: i32,
+    pub name: String,
+    pub mime_type: String,
 }
 
 #[derive(Clone)]
@@ -29,214 +98,622 @@ pub enum LoginStatus {
 }
 
 impl BaleClient {
+    /// Client of `next-api.bale.ai` with the settings of its web client.
     pub fn new(phone_number: u64) -> Self {
-        BaleClient {
-            client: Client::new_with_encoding(
-                "https://next-api.bale.ai".to_string(),
-                Encoding::Base64,
-            ),
-            login_status: LoginStatus::WaitingForNumber,
-            phone_number,
+        Self::builder(phone_number)
+            .build()
+            .expect("default settings are valid")
+    }
+
+    pub fn builder(phone_number: u64) -> BaleClientBuilder {
+        BaleClientBuilder::new(phone_number)
+    }
+
+    /// Paces the messages sent with [`BaleClient::queue_message`].
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn login_status(&self) -> LoginStatus {
+        self.login_status.borrow().clone()
+    }
+
+    /// Notifies every change of the login status, like the session expiring
+    /// while the client is in use.
+    pub fn watch_login_status(&self) -> watch::Receiver<LoginStatus> {
+        self.login_status.subscribe()
+    }
+
+    /// Time the current JWT expires, from its `exp` claim.
+    pub fn token_expiry(&self) -> Option<SystemTime> {
+        let jwt = parse_jwt(&self.jwt()?)?;
+        Some(UNIX_EPOCH + Duration::from_millis(jwt.exp as u64))
+    }
+
+    /// Marks the session as expired, so requests stop until logging in again.
+    pub fn expire(&self) {
+        self.set_login_status(LoginStatus::Expired);
+    }
+
+    fn set_login_status(&self, status: LoginStatus) {
+        self.login_status.send_replace(status);
+    }
+
+    fn jwt(&self) -> Option<String> {
+        if let LoginStatus::LoggedIn(jwt, _user_id) = &*self.login_status.borrow() {
+            Some(jwt.clone())
+        } else {
+            None
         }
     }
 
-    pub async fn login(&mut self) -> LoginStatus {
+    /// Saves the JWT of successful logins in `store`, to be reused with [`BaleClient::saved_jwt`].
+    pub fn with_credential_store(mut self, store: CredentialStore) -> Self {
+        self.credential_store = Some(store);
+        self
+    }
+
+    /// Returns the JWT saved for this phone number, unless it has expired.
+    pub fn saved_jwt(&self) -> Option<String> {
+        let credentials = self.credential_store.as_ref()?.load(self.phone_number)?;
+        if is_jwt_expired(&credentials.jwt) {
+            debug!("saved jwt of {} has expired", self.phone_number);
+            return None;
+        }
+        Some(credentials.jwt)
+    }
+
+    pub async fn login(&self) -> Result<LoginStatus, Error> {
         let mut client = auth_client::AuthClient::new(self.client.clone());
 
         let request = tonic::Request::new(StartPhoneAuthRequest {
             phone_number: self.phone_number,
-            client_version: 4,
-            api_key: API_KEY.to_string(),
-            user_agent: "Firefox, macOS".to_string(),
-            user_agent_string: "Firefox, macOS".to_string(),
+            client_version: self.client_version,
+            api_key: self.api_key.clone(),
+            user_agent: self.user_agent.clone(),
+            user_agent_string: self.user_agent.clone(),
         });
 
-        let response = match client.start_phone_auth(request).await {
-            Ok(response) => response.into_inner(),
-            Err(status) => {
-                panic!("{} : {}", status.code(), status.message())
-            }
-        };
+        let response = client.start_phone_auth(request).await?.into_inner();
 
-        self.login_status = LoginStatus::WaitingForValidationCode(response.login_hash);
+        self.set_login_status(LoginStatus::WaitingForValidationCode(response.login_hash));
 
         if response.registered != 1 {
-            return LoginStatus::NotRegistered;
+            return Ok(LoginStatus::NotRegistered);
         }
 
         let _conf = self.fetch_configs().await;
-        self.login_status.clone()
+        Ok(self.login_status())
     }
 
-    pub async fn login_with(&mut self, jwt: String) -> LoginStatus {
-        self.login_status = LoginStatus::LoggedIn(jwt.clone(), 0);
-        if let Some(_configs) = self.fetch_configs().await {
-            let user_data = get_user_data_from_jwt(jwt.clone());
-            self.login_status = LoginStatus::LoggedIn(jwt, user_data.user_id);
-        } else {
-            self.login_status = LoginStatus::Expired;
+    pub async fn login_with(&self, jwt: String) -> Result<LoginStatus, Error> {
+        let user_data = get_user_data_from_jwt(&jwt)?;
+        self.set_login_status(LoginStatus::LoggedIn(jwt.clone(), 0));
+        match self.fetch_configs().await {
+            Ok(_configs) => {
+                self.set_login_status(LoginStatus::LoggedIn(jwt, user_data.user_id));
+            }
+            Err(Error::AuthExpired) => {}
+            Err(err) => {
+                self.set_login_status(LoginStatus::WaitingForNumber);
+                return Err(err);
+            }
         }
-        self.login_status.clone()
+        Ok(self.login_status())
     }
 
-    async fn fetch_configs(&mut self) -> Option<HashMap<String, String>> {
+    async fn fetch_configs(&self) -> Result<HashMap<String, String>, Error> {
         let mut client = configs_client::ConfigsClient::new(self.client.clone());
 
-        let mut request = tonic::Request::new(GetParametersRequest {});
-
-        let jwt = if let LoginStatus::LoggedIn(jwt, _user_id) = &self.login_status {
-            jwt
-        } else {
-            return None;
-        };
-
-        request
-            .metadata_mut()
-            .insert("auth-jwt", jwt.parse().unwrap());
-
-        let mut response = match client.get_parameters(request).await {
-            Ok(response) => response.into_inner(),
-            Err(status) => {
-                trace!("{} : {}", status.code(), status.message());
-                return None;
-            }
-        };
-
-        if let Ok(Some(reply)) = response.message().await {
-            Some(
-                reply
-                    .configs
-                    .into_iter()
-                    .fold(HashMap::new(), |mut configs, config| {
-                        configs.insert(config.key, config.value);
-                        configs
-                    }),
-            )
-        } else {
-            None
-        }
+        let request = self.authorize(GetParametersRequest {})?;
+
+        let mut response = client
+            .get_parameters(request)
+            .await
+            .map_err(|status| self.rpc_error(status))?
+            .into_inner();
+
+        let reply = response
+            .message()
+            .await?
+            .ok_or_else(|| Error::malformed("configs"))?;
+        Ok(reply
+            .configs
+            .into_iter()
+            .fold(HashMap::new(), |mut configs, config| {
+                configs.insert(config.key, config.value);
+                configs
+            }))
     }
 
-    pub async fn validate_code(&mut self, login_code: &str) -> Option<UserData> {
+    pub async fn validate_code(&self, login_code: &str) -> Result<UserData, Error> {
         let mut client = auth_client::AuthClient::new(self.client.clone());
 
         let login_hash =
-            if let LoginStatus::WaitingForValidationCode(login_hash) = &self.login_status {
+            if let LoginStatus::WaitingForValidationCode(login_hash) = self.login_status() {
                 login_hash
             } else {
-                return None;
+                return Err(Error::AuthExpired);
             };
 
         let request = tonic::Request::new(ValidateCodeRequest {
-            login_hash: login_hash.to_string(),
+            login_hash,
             login_code: login_code.to_string(),
             validate_code_request_sub_request: Some(ValidateCodeRequestSubRequest { unknown: 1 }),
         });
 
-        let response = match client.validate_code(request).await {
-            Ok(response) => response.into_inner(),
-            Err(status) => {
-                panic!("{} : {}", status.code(), status.message())
-            }
-        };
-
-        self.login_status = LoginStatus::LoggedIn(
-            response.auth.clone().unwrap().jwt,
-            response.profile.as_ref().unwrap().user_id,
-        );
+        let response = client.validate_code(request).await?.into_inner();
         trace!("{:#?}", &response);
 
-        Some(UserData {
+        let jwt = response.auth.ok_or_else(|| Error::malformed("auth"))?.jwt;
+        let user_id = response
+            .profile
+            .ok_or_else(|| Error::malformed("profile"))?
+            .user_id;
+        self.set_login_status(LoginStatus::LoggedIn(jwt.clone(), user_id));
+
+        if let Some(store) = &self.credential_store {
+            let credentials = Credentials { jwt, user_id };
+            if let Err(err) = store.save(self.phone_number, &credentials) {
+                error!("could not save credentials : {}", err);
+            }
+        }
+
+        Ok(UserData {
             app_id: 4,
             auth_id: "".to_string(), // FIXME
             auth_sid: 0,             // FIXME
             service: "web_lite".to_string(),
-            user_id: response.profile.clone().unwrap().user_id,
+            user_id,
         })
     }
 
-    pub async fn send_message(&self, user_id: u32, message: String) {
+    pub async fn send_message(&self, peer: Peer, message: String) -> Result<(), Error> {
+        debug!("Sending message to {} : {}", peer, &message);
+
+        self.send(
+            peer,
+            MessagingMessage {
+                text_message: Some(MessagingTextMessage { text: message }),
+                document_message: None,
+            },
+        )
+        .await
+    }
+
+    /// Uploads `content` as a file and sends it to the chat as a document.
+    pub async fn send_document(
+        &self,
+        peer: Peer,
+        name: String,
+        content: Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut client = files_client::FilesClient::new(self.client.clone());
+
+        debug!(
+            "Sending document {} to {} : {} bytes",
+            &name,
+            peer,
+            content.len()
+        );
+
+        let file_size = content.len() as i32;
+        let request = self.authorize(GetFileUploadUrlRequest {
+            expected_size: file_size,
+        })?;
+
+        let response = client
+            .get_file_upload_url(request)
+            .await
+            .map_err(|status| self.rpc_error(status))?
+            .into_inner();
+        let file = response
+            .file
+            .ok_or_else(|| Error::malformed("file location"))?;
+
+        self.http_client
+            .put(&response.url)
+            .body(content)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        self.send(
+            peer,
+            MessagingMessage {
+                text_message: None,
+                document_message: Some(MessagingDocumentMessage {
+                    file_id: file.file_id,
+                    access_hash: file.access_hash,
+                    file_size,
+                    name,
+                    mime_type: "application/octet-stream".to_string(),
+                }),
+            },
+        )
+        .await
+    }
+
+    /// Queues a text message, to be sent by [`BaleClient::run_outbox`].
+    ///
+    /// Texts queued for the same chat may be joined with new lines and sent
+    /// as a single message.
+    pub fn queue_message(&self, peer: Peer, text: String) {
+        self.outbox.push(Outgoing::Text { peer, text });
+    }
+
+    /// Queues a document, to be sent by [`BaleClient::run_outbox`].
+    pub fn queue_document(&self, peer: Peer, name: String, content: Vec<u8>) {
+        self.outbox.push(Outgoing::Document {
+            peer,
+            name,
+            content,
+        });
+    }
+
+    /// Queues deleting messages of a chat, for all of its members.
+    pub fn queue_delete(&self, peer: Peer, ids: Vec<MessageId>) {
+        self.outbox.push(Outgoing::Delete { peer, ids });
+    }
+
+    /// Size of the send queue and counters of sent messages.
+    pub fn outbox_stats(&self) -> watch::Receiver<OutboxStats> {
+        self.outbox.stats()
+    }
+
+    /// Sends the queued messages, no faster than the rate limit.
+    ///
+    /// Messages are sent again after rate limits, network errors and logging
+    /// in again. Other failures are reported to `failed_tx` with the chat the
+    /// message was for. Returns once `failed_tx` is closed.
+    pub async fn run_outbox(&self, failed_tx: Sender<(Peer, Error)>) -> Result<(), Error> {
+        let mut bucket = TokenBucket::new(self.rate_limit);
+        let mut login_status = self.watch_login_status();
+        let mut attempts = 0;
+        loop {
+            let message = self.outbox.pop().await;
+            bucket.take().await;
+
+            let res = match &message {
+                Outgoing::Text { peer, text } => self.send_message(*peer, text.clone()).await,
+                Outgoing::Document {
+                    peer,
+                    name,
+                    content,
+                } => {
+                    self.send_document(*peer, name.clone(), content.clone())
+                        .await
+                }
+                Outgoing::Delete { peer, ids } => self.delete_messages(*peer, ids).await,
+            };
+            attempts += 1;
+
+            match res {
+                Ok(()) => {
+                    self.outbox.record_sent();
+                    attempts = 0;
+                }
+                Err(Error::RateLimited(delay)) => {
+                    let delay = delay.unwrap_or(RATE_LIMIT_DELAY);
+                    warn!("rate limited, waiting {:?}", delay);
+                    self.outbox.record_rate_limited();
+                    bucket.drain();
+                    self.outbox.push_front(message);
+                    tokio::time::sleep(delay).await;
+                }
+                Err(Error::AuthExpired) => {
+                    self.outbox.push_front(message);
+                    while self.jwt().is_none() {
+                        if login_status.changed().await.is_err() {
+                            return Err(Error::AuthExpired);
+                        }
+                    }
+                }
+                Err(err) if err.is_transient() && attempts < MAX_SEND_ATTEMPTS => {
+                    warn!("could not send message, retrying : {}", err);
+                    self.outbox.push_front(message);
+                    tokio::time::sleep(MIN_RECONNECT_DELAY * attempts).await;
+                }
+                Err(err) => {
+                    attempts = 0;
+                    if failed_tx.send((message.peer(), err)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deletes messages of a chat, for all of its members.
+    pub async fn delete_messages(&self, peer: Peer, ids: &[MessageId]) -> Result<(), Error> {
         let mut client = messaging_client::MessagingClient::new(self.client.clone());
 
-        debug!("Sending message to user #{} : {}", user_id, &message);
+        debug!("Deleting {} messages of {}", ids.len(), peer);
+
+        let request = self.authorize(DeleteMessagesRequest {
+            peer: Some(peer.into()),
+            rids: ids.iter().map(|id| id.rid).collect(),
+            dates: ids.iter().map(|id| id.date).collect(),
+            just_for_me: false,
+        })?;
+
+        let response = client
+            .delete_messages(request)
+            .await
+            .map_err(|status| match status.code() {
+                tonic::Code::NotFound => Error::UnknownPeer(peer),
+                _ => self.rpc_error(status),
+            })?
+            .into_inner();
+
+        trace!("{:#?}", response);
+        Ok(())
+    }
 
-        let jwt = if let LoginStatus::LoggedIn(jwt, _user_id) = &self.login_status {
-            jwt
-        } else {
-            return;
-        };
+    /// Downloads the file attached to a document message.
+    pub async fn download_file(&self, document: &Document) -> Result<Vec<u8>, Error> {
+        let mut client = files_client::FilesClient::new(self.client.clone());
 
-        let mut request = tonic::Request::new(SendMessageRequest {
-            peer: Some(MessagingPeer {
-                unknown: 1,
-                user_id,
-            }),
-            rid: rand::random(),
-            message: Some(MessagingMessage {
-                text_message: Some(MessagingTextMessage { text: message }),
+        let request = self.authorize(GetFileUrlRequest {
+            file: Some(FileLocation {
+                file_id: document.file_id,
+                access_hash: document.access_hash,
             }),
-        });
+        })?;
+
+        let response = client
+            .get_file_url(request)
+            .await
+            .map_err(|status| self.rpc_error(status))?
+            .into_inner();
+
+        let content = self
+            .http_client
+            .get(&response.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(content.to_vec())
+    }
 
-        request
-            .metadata_mut()
-            .insert("auth-jwt", jwt.parse().unwrap());
+    async fn send(&self, peer: Peer, message: MessagingMessage) -> Result<(), Error> {
+        let mut client = messaging_client::MessagingClient::new(self.client.clone());
 
-        let response = match client.send_message(request).await {
-            Ok(response) => response.into_inner(),
-            Err(status) => {
-                panic!("{} : {}", status.code(), status.message())
+        let request = self.authorize(SendMessageRequest {
+            peer: Some(peer.into()),
+            rid: rand::random(),
+            message: Some(message),
+        })?;
+
+        let response = client
+            .send_message(request)
+            .await
+            .map_err(|status| match status.code() {
+                tonic::Code::NotFound => Error::UnknownPeer(peer),
+                _ => self.rpc_error(status),
+            })?
+            .into_inner();
+
+        trace!("{:#?}", response);
+        Ok(())
+    }
+
+    /// Forwards the messages sent to this user and to its groups to `tx` until
+    /// `tx` is closed.
+    ///
+    /// The update stream is opened again with an exponential backoff whenever
+    /// it fails or ends, and the messages sent while it was down are fetched
+    /// with `GetDifference`. Failing to fetch them doesn't stop the stream,
+    /// since `GetDifference` was not seen in use by the web client. While the
+    /// session is expired, it waits for logging in again.
+    pub async fn subscribe_to_updates(&self, tx: Sender<Received>) -> Result<(), Error> {
+        let mut login_status = self.watch_login_status();
+        let mut updates = Updates::new(tx);
+        let mut delay = MIN_RECONNECT_DELAY;
+        loop {
+            match self.stream_updates(&mut updates, &mut delay).await {
+                Ok(()) => warn!("update stream ended"),
+                Err(Error::AuthExpired) => {
+                    if login_status.changed().await.is_err() {
+                        return Err(Error::AuthExpired);
+                    }
+                    continue;
+                }
+                Err(err) => warn!("update stream failed : {}", err),
+            }
+            if updates.tx.is_closed() {
+                return Ok(());
             }
-        };
 
-        trace!("{:#?}", response)
+            info!("reconnecting to update stream in {:?}", delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
     }
 
-    pub async fn subscribe_to_updates(&self, tx: Sender<(u32, String)>) {
+    async fn stream_updates(
+        &self,
+        updates: &mut Updates,
+        delay: &mut Duration,
+    ) -> Result<(), Error> {
         let mut client = maviz_stream_client::MavizStreamClient::new(self.client.clone());
 
-        let jwt = if let LoginStatus::LoggedIn(jwt, _user_id) = &self.login_status {
-            jwt
-        } else {
-            return;
-        };
+        let request = self.authorize(SubscribeToUpdatesRequest {})?;
 
-        let mut request = tonic::Request::new(SubscribeToUpdatesRequest {});
+        let mut response = client
+            .subscribe_to_updates(request)
+            .await
+            .map_err(|status| self.rpc_error(status))?
+            .into_inner();
+        *delay = MIN_RECONNECT_DELAY;
 
-        request
-            .metadata_mut()
-            .insert("auth-jwt", jwt.parse().unwrap());
-
-        let mut response = match client.subscribe_to_updates(request).await {
-            Ok(response) => response.into_inner(),
-            Err(status) => {
-                panic!("{} : {}", status.code(), status.message())
+        // Messages that arrive while catching up are buffered by the stream,
+        // and the ones seen twice are dropped by `updates`.
+        if let Some(timestamp) = updates.last_timestamp {
+            if let Err(err) = self.get_difference(timestamp, updates).await {
+                warn!("could not fetch missed messages : {}", err);
             }
-        };
+        }
 
-        while let Some(message) = response.message().await.unwrap() {
+        while let Some(message) = response
+            .message()
+            .await
+            .map_err(|status| self.rpc_error(status))?
+        {
             trace!("Received: {:?}", message);
 
-            let mut msg: Option<(u32, String)> = None;
-            if let Some(request) = message.request {
-                if let Some(receive_message) = request.receive_message {
-                    let sender_id = receive_message.sender_id;
-                    if let Some(message) = receive_message.message {
-                        if let Some(text_message) = message.text_message {
-                            msg = Some((sender_id, text_message.text));
-                        }
-                    }
+            if let Some(receive_message) =
+                message.request.and_then(|request| request.receive_message)
+            {
+                if !updates.forward(receive_message).await {
+                    return Ok(());
                 }
             }
+        }
+        Ok(())
+    }
+
+    /// Forwards the messages received after `timestamp`.
+    async fn get_difference(&self, mut timestamp: u64, updates: &mut Updates) -> Result<(), Error> {
+        let mut client = maviz_stream_client::MavizStreamClient::new(self.client.clone());
 
-            if let Some(msg_with_user) = msg {
-                if let Some(err) = tx.send(msg_with_user).await.err() {
-                    error!("{}", err);
+        loop {
+            let request = self.authorize(GetDifferenceRequest {
+                timestamp,
+                limit: DIFFERENCE_LIMIT,
+            })?;
+
+            let response = client
+                .get_difference(request)
+                .await
+                .map_err(|status| self.rpc_error(status))?
+                .into_inner();
+            debug!("fetched {} missed messages", response.updates.len());
+
+            for update in response.updates {
+                if !updates.forward(update).await {
+                    return Ok(());
                 }
             }
+
+            match updates.last_timestamp {
+                Some(last_timestamp) if response.need_more && last_timestamp > timestamp => {
+                    timestamp = last_timestamp
+                }
+                _ => return Ok(()),
+            }
         }
     }
+
+    /// Wraps `message` in a request carrying the JWT of the session.
+    fn authorize<T>(&self, message: T) -> Result<tonic::Request<T>, Error> {
+        let jwt = self.jwt().ok_or(Error::AuthExpired)?;
+        let mut request = tonic::Request::new(message);
+        request
+            .metadata_mut()
+            .insert("auth-jwt", jwt.parse().map_err(|_| Error::AuthExpired)?);
+        Ok(request)
+    }
+
+    /// Converts the error of an authorized request, expiring the session if
+    /// the server rejected the JWT.
+    fn rpc_error(&self, status: tonic::Status) -> Error {
+        let err = Error::from(status);
+        if let Error::AuthExpired = err {
+            warn!("session expired");
+            self.expire();
+        }
+        err
+    }
 }
 
+/// Messages already forwarded to the subscriber, so the ones fetched again
+/// after a reconnect are not forwarded twice.
+struct Updates {
+    tx: Sender<Received>,
+    last_timestamp: Option<u64>,
+    seen: HashSet<u64>,
+    seen_order: VecDeque<u64>,
+}
+
+impl Updates {
+    fn new(tx: Sender<Received>) -> Self {
+        Updates {
+            tx,
+            last_timestamp: None,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+        }
+    }
+
+    /// Returns false once the subscriber is gone.
+    async fn forward(&mut self, update: ReceiveMessageRequest) -> bool {
+        if !self.seen.insert(update.rid) {
+            trace!("dropping duplicated message {}", update.rid);
+            return true;
+        }
+        self.seen_order.push_back(update.rid);
+        if self.seen_order.len() > SEEN_MESSAGES {
+            if let Some(rid) = self.seen_order.pop_front() {
+                self.seen.remove(&rid);
+            }
+        }
+        self.last_timestamp = self.last_timestamp.max(Some(update.timestamp));
+
+        let sender_id = update.sender_id;
+        // Private chats are named after the other user.
+        let peer = match &update.peer {
+            Some(peer) => match Peer::from_maviz(peer) {
+                Some(peer) => peer,
+                None => {
+                    trace!(
+                        "dropping message {} from an unknown kind of chat",
+                        update.rid
+                    );
+                    return true;
+                }
+            },
+            None => Peer::User(sender_id),
+        };
+        let message = match update.message {
+            Some(message) => message,
+            None => return true,
+        };
+        let message = if let Some(text_message) = message.text_message {
+            Message::Text(text_message.text)
+        } else if let Some(document_message) = message.document_message {
+            Message::Document(Document {
+                file_id: document_message.file_id,
+                access_hash: document_message.access_hash,
+                size: document_message.file_size,
+                name: document_message.name,
+                mime_type: document_message.mime_type,
+            })
+        } else {
+            return true;
+        };
+
+        let received = Received {
+            peer,
+            sender_id,
+            id: MessageId {
+                rid: update.rid,
+                date: update.timestamp,
+            },
+            message,
+        };
+        if let Err(err) = self.tx.send(received).await {
+            error!("{}", err);
+            return false;
+        }
+        true
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 struct Jwt {
     exp: i64,
@@ -259,9 +736,58 @@ pub struct UserData {
     pub user_id: u32,
 }
 
-fn get_user_data_from_jwt(jtw: String) -> UserData {
-    let jwt_payload = jtw.split(".").collect::<Vec<&str>>()[1].clone();
-    let jwt_payload_data = base64::decode(jwt_payload).unwrap();
-    let jwt: Jwt = serde_json::from_slice(jwt_payload_data.as_slice()).unwrap();
-    jwt.payload
+fn get_user_data_from_jwt(jwt: &str) -> Result<UserData, Error> {
+    parse_jwt(jwt)
+        .map(|jwt| jwt.payload)
+        .ok_or_else(|| Error::MalformedResponse("could not parse jwt".to_string()))
+}
+
+fn parse_jwt(jwt: &str) -> Option<Jwt> {
+    let jwt_payload = jwt.split('.').nth(1)?;
+    let jwt_payload_data = base64::decode_config(jwt_payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(jwt_payload_data.as_slice()).ok()
+}
+
+/// Tells whether the `exp` claim, in milliseconds, is in the past. JWTs that
+/// can't be parsed are considered expired.
+fn is_jwt_expired(jwt: &str) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    match parse_jwt(jwt) {
+        Some(jwt) => jwt.exp <= now,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Payload of the client jwt in the README, signature stripped.
+    const JWT: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJleHAiOjE2MzM1OTExODI1OTQsImlhdCI6MTYyODQwNzE4MjU5NCwiaXNzIjoiaHR0cHM6Ly93ZWIuYmFsZS5haSIsInBheWxvYWQiOnsiYXBwX2lkIjo0LCJhdXRoX2lkIjoiMTgxOTc2OTA4MTk4MzU4Njk3MCIsImF1dGhfc2lkIjo1ODE0NzYwMTYsInNlcnZpY2UiOiJ3ZWJfbGl0ZSIsInVzZXJfaWQiOjcwMzcyOTQ0N319.";
+
+    #[test]
+    fn parses_jwt_claims() {
+        let jwt = parse_jwt(JWT).unwrap();
+        assert_eq!(jwt.exp, 1633591182594);
+        assert_eq!(jwt.payload.user_id, 703729447);
+        assert!(is_jwt_expired(JWT));
+        assert!(is_jwt_expired("not a jwt"));
+    }
+
+    #[test]
+    fn classifies_rpc_errors() {
+        assert!(matches!(
+            Error::from(tonic::Status::unauthenticated("")),
+            Error::AuthExpired
+        ));
+        assert!(matches!(
+            Error::from(tonic::Status::resource_exhausted("")),
+            Error::RateLimited(None)
+        ));
+        assert!(Error::from(tonic::Status::unavailable("")).is_transient());
+        assert!(!Error::from(tonic::Status::invalid_argument("")).is_transient());
+    }
 }
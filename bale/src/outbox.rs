@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::{MessageId, Peer, MAX_TEXT_MESSAGE_LENGTH};
+
+/// How fast queued messages are sent.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Messages sent per second on average.
+    pub per_second: f64,
+    /// Messages that can be sent at once after being idle.
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_second: 2.0,
+            burst: 10,
+        }
+    }
+}
+
+/// Counters of the send queue, to see whether the messenger keeps up.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutboxStats {
+    /// Messages waiting to be sent.
+    pub queued: usize,
+    /// Messages sent since the start, after batching.
+    pub sent: u64,
+    /// Number of times the server asked to slow down.
+    pub rate_limited: u64,
+}
+
+pub(crate) enum Outgoing {
+    Text {
+        peer: Peer,
+        text: String,
+    },
+    Document {
+        peer: Peer,
+        name: String,
+        content: Vec<u8>,
+    },
+    Delete {
+        peer: Peer,
+        ids: Vec<MessageId>,
+    },
+}
+
+impl Outgoing {
+    pub(crate) fn peer(&self) -> Peer {
+        match *self {
+            Outgoing::Text { peer, .. } => peer,
+            Outgoing::Document { peer, .. } => peer,
+            Outgoing::Delete { peer, .. } => peer,
+        }
+    }
+}
+
+/// Messages waiting for their turn to be sent.
+///
+/// Texts queued for the same chat are joined with new lines into a single
+/// message as long as they fit, and so are deletions, so bursts cost fewer
+/// requests.
+pub(crate) struct Outbox {
+    queue: Mutex<VecDeque<Outgoing>>,
+    queued: Notify,
+    stats: watch::Sender<OutboxStats>,
+}
+
+impl Outbox {
+    pub(crate) fn new() -> Self {
+        Outbox {
+            queue: Mutex::new(VecDeque::new()),
+            queued: Notify::new(),
+            stats: watch::channel(OutboxStats::default()).0,
+        }
+    }
+
+    pub(crate) fn push(&self, message: Outgoing) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(message);
+        let queued = queue.len();
+        drop(queue);
+
+        self.stats.send_modify(|stats| stats.queued = queued);
+        self.queued.notify_one();
+    }
+
+    /// Puts back a message that could not be sent, to be sent first.
+    pub(crate) fn push_front(&self, message: Outgoing) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_front(message);
+        let queued = queue.len();
+        drop(queue);
+
+        self.stats.send_modify(|stats| stats.queued = queued);
+    }
+
+    /// Waits for the next message, joined with the texts queued after it.
+    pub(crate) async fn pop(&self) -> Outgoing {
+        loop {
+            if let Some(message) = self.try_pop() {
+                return message;
+            }
+            self.queued.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<Outgoing> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut message = queue.pop_front()?;
+
+        match &mut message {
+            Outgoing::Text { peer, text } => {
+                let mut length = text.chars().count();
+                while let Some(Outgoing::Text {
+                    peer: next_peer,
+                    text: next,
+                }) = queue.front()
+                {
+                    let next_length = next.chars().count();
+                    if next_peer != peer || length + 1 + next_length > MAX_TEXT_MESSAGE_LENGTH {
+                        break;
+                    }
+                    text.push('\n');
+                    text.push_str(next);
+                    length += 1 + next_length;
+                    queue.pop_front();
+                }
+            }
+            Outgoing::Delete { peer, ids } => {
+                while let Some(Outgoing::Delete {
+                    peer: next_peer,
+                    ids: next,
+                }) = queue.front_mut()
+                {
+                    if next_peer != peer {
+                        break;
+                    }
+                    ids.append(next);
+                    queue.pop_front();
+                }
+            }
+            Outgoing::Document { .. } => {}
+        }
+
+        let queued = queue.len();
+        drop(queue);
+        self.stats.send_modify(|stats| stats.queued = queued);
+        Some(message)
+    }
+
+    pub(crate) fn stats(&self) -> watch::Receiver<OutboxStats> {
+        self.stats.subscribe()
+    }
+
+    pub(crate) fn record_sent(&self) {
+        self.stats.send_modify(|stats| stats.sent += 1);
+    }
+
+    pub(crate) fn record_rate_limited(&self) {
+        self.stats.send_modify(|stats| stats.rate_limited += 1);
+    }
+}
+
+/// Token bucket pacing the messages taken from the outbox.
+pub(crate) struct TokenBucket {
+    rate_limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate_limit: RateLimit) -> Self {
+        TokenBucket {
+            rate_limit,
+            tokens: rate_limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Waits until a message can be sent.
+    pub(crate) async fn take(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.rate_limit.per_second;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+
+    /// Drops the saved up tokens, after the server asked to slow down.
+    pub(crate) fn drain(&mut self) {
+        self.tokens = 0.0;
+        self.updated = Instant::now();
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate_limit.per_second).min(self.rate_limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(peer: Peer, text: &str) -> Outgoing {
+        Outgoing::Text {
+            peer,
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(outbox: &Outbox) -> Vec<(Peer, String)> {
+        std::iter::from_fn(|| outbox.try_pop())
+            .map(|message| match message {
+                Outgoing::Text { peer, text } => (peer, text),
+                Outgoing::Document { peer, name, .. } => (peer, name),
+                Outgoing::Delete { peer, ids } => (peer, format!("{:?}", ids)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batches_texts_of_same_chat() {
+        let (alice, bob, group) = (Peer::User(1), Peer::User(2), Peer::Group(2));
+        let outbox = Outbox::new();
+        outbox.push(text(alice, "a"));
+        outbox.push(text(alice, "b"));
+        outbox.push(text(bob, "c"));
+        outbox.push(text(group, "d"));
+        outbox.push(Outgoing::Document {
+            peer: group,
+            name: "e".to_string(),
+            content: Vec::new(),
+        });
+        outbox.push(text(group, "f"));
+        outbox.push(text(group, &"g".repeat(MAX_TEXT_MESSAGE_LENGTH)));
+        let id = |rid| MessageId { rid, date: 0 };
+        outbox.push(Outgoing::Delete {
+            peer: group,
+            ids: vec![id(1)],
+        });
+        outbox.push(Outgoing::Delete {
+            peer: group,
+            ids: vec![id(2)],
+        });
+
+        assert_eq!(
+            texts(&outbox),
+            vec![
+                (alice, "a\nb".to_string()),
+                (bob, "c".to_string()),
+                (group, "d".to_string()),
+                (group, "e".to_string()),
+                (group, "f".to_string()),
+                (group, "g".repeat(MAX_TEXT_MESSAGE_LENGTH)),
+                (group, format!("{:?}", vec![id(1), id(2)])),
+            ]
+        );
+        assert_eq!(outbox.stats().borrow().queued, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_after_burst() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let start = Instant::now();
+        for _ in 0..3 {
+            bucket.take().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        bucket.take().await;
+        bucket.take().await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::{Error, MavizPeer, MavizPeerType, MessagingPeer, MessagingPeerType};
+
+/// Chat messages are sent to and received in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// Private chat with a user.
+    User(u32),
+    /// Group, read by all of its members.
+    Group(u32),
+}
+
+impl Peer {
+    /// Chat of an update, or `None` if Bale added a kind of chat this client
+    /// does not know.
+    pub(crate) fn from_maviz(peer: &MavizPeer) -> Option<Peer> {
+        match MavizPeerType::from_i32(peer.r#type)? {
+            MavizPeerType::Private => Some(Peer::User(peer.id)),
+            MavizPeerType::Group => Some(Peer::Group(peer.id)),
+            MavizPeerType::Unknown => None,
+        }
+    }
+}
+
+impl From<Peer> for MessagingPeer {
+    fn from(peer: Peer) -> MessagingPeer {
+        let (peer_type, id) = match peer {
+            Peer::User(id) => (MessagingPeerType::Private, id),
+            Peer::Group(id) => (MessagingPeerType::Group, id),
+        };
+        MessagingPeer {
+            r#type: peer_type as i32,
+            id,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Peer::User(id) => write!(f, "user #{}", id),
+            Peer::Group(id) => write!(f, "group #{}", id),
+        }
+    }
+}
+
+/// Parses `user:123` or `group:123`, a bare id being a user. Channels are
+/// rejected, since how Bale addresses them is not known.
+impl FromStr for Peer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Peer, Error> {
+        let bad_peer = || Error::InvalidSettings(format!("bad peer {}", s));
+
+        let (kind, id) = s.split_once(':').unwrap_or(("user", s));
+        let id = id.trim().parse().map_err(|_| bad_peer())?;
+        match kind.trim().to_lowercase().as_str() {
+            "user" => Ok(Peer::User(id)),
+            "group" => Ok(Peer::Group(id)),
+            "channel" => Err(Error::InvalidSettings(format!(
+                "channels are not supported, use a group instead of {}",
+                s
+            ))),
+            _ => Err(bad_peer()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_peers() {
+        assert_eq!("123".parse::<Peer>().unwrap(), Peer::User(123));
+        assert_eq!("user:123".parse::<Peer>().unwrap(), Peer::User(123));
+        assert_eq!("group:123".parse::<Peer>().unwrap(), Peer::Group(123));
+        assert!("Channel:123".parse::<Peer>().is_err());
+        assert!("group:".parse::<Peer>().is_err());
+        assert!("chat:123".parse::<Peer>().is_err());
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+#![allow(clippy::result_large_err)]
 
 // This file is based on https://github.com/alce/tonic/blob/86bbb1d5a4844882dec81bef7c1a554bd9464adf/tonic-web/tonic-web/src/call.rs
 
@@ -367,8 +368,10 @@ where
             }
             match ready!(self.as_mut().poll_decode(cx)) {
                 Some(Err(e)) => return Poll::Ready(Err(e)),
-                _ => {}
-            };
+                // The body ended without trailers, like a dropped stream.
+                None => return Poll::Ready(Ok(None)),
+                Some(Ok(_)) => {}
+            }
         }
     }
 
@@ -402,7 +405,7 @@ impl Encoding {
         Self::from_header(headers.get(header::ACCEPT))
     }
 
-    pub(crate) fn to_content_type(&self) -> &'static str {
+    pub(crate) fn to_content_type(self) -> &'static str {
         match self {
             Encoding::Base64 => GRPC_WEB_TEXT_PROTO,
             Encoding::None => GRPC_WEB_PROTO,
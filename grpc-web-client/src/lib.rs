@@ -29,25 +29,37 @@ impl fmt::Display for ClientError {
 pub struct Client {
     base_uri: String,
     encoding: Encoding,
+    http_client: reqwest::Client,
 }
 
 impl Client {
     pub fn new(base_uri: String) -> Self {
-        Client {
-            base_uri,
-            encoding: Encoding::None,
-        }
+        Self::new_with_encoding(base_uri, Encoding::None)
     }
 
     pub fn new_with_encoding(base_uri: String, encoding: Encoding) -> Self {
-        Client { base_uri, encoding }
+        Self::new_with_http_client(base_uri, encoding, reqwest::Client::new())
+    }
+
+    /// Sends the requests with `http_client`, to use its TLS settings.
+    pub fn new_with_http_client(
+        base_uri: String,
+        encoding: Encoding,
+        http_client: reqwest::Client,
+    ) -> Self {
+        Client {
+            base_uri,
+            encoding,
+            http_client,
+        }
     }
 
     async fn request(self, rpc: Request<BoxBody>) -> Result<Response<BoxBody>, ClientError> {
         let mut uri = rpc.uri().to_string();
         uri.insert_str(0, &self.base_uri);
 
-        let mut req = reqwest::Client::new()
+        let mut req = self
+            .http_client
             .post(uri)
             .version(reqwest::Version::HTTP_11); // TODO: Shouldn't this be HTTP_2 at least?
 
@@ -97,7 +109,7 @@ impl GrpcService<BoxBody> for Client {
     type ResponseBody = BoxBody;
     type Error = ClientError;
     type Future =
-        Pin<Box<(dyn Future<Output = Result<Response<BoxBody>, ClientError>> + Send + 'static)>>;
+        Pin<Box<dyn Future<Output = Result<Response<BoxBody>, ClientError>> + Send + 'static>>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))